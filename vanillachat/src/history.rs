@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use chrono::{DateTime,Local};
+use vanillaproto::Receipt;
+use crate::PrintedLine;
+use crate::sanitise::plain_text;
+
+//how many messages to remember for /edit, /delete, /reply and receipts
+const HISTORY_LENGTH: usize = 1000;
+//how much of a message a reply shows
+const QUOTE_LENGTH: usize = 30;
+
+//a chat message either of us sent
+pub struct HistoryEntry {
+	//what we show in front of the line and what commands refer to
+	pub number: usize,
+	pub ours: bool,
+	//the sender's id for it, unique per sender
+	pub id: u64,
+	pub author: String,
+	pub body: String,
+	pub edited: bool,
+	pub deleted: bool,
+	//only used for our own messages
+	pub receipt: Option<Receipt>,
+	//what it is replying to, as it was when the reply was sent
+	pub quote: Option<String>,
+	//when it arrived, only shown for messages the daemon held for us
+	pub time: Option<DateTime<Local>>,
+	pub line: Option<PrintedLine>,
+}
+
+//recent messages, oldest first
+pub struct History {
+	entries: VecDeque<HistoryEntry>,
+	next_number: usize,
+}
+
+impl HistoryEntry {
+	pub fn new(ours: bool, id: u64, author: &str, body: &str) -> HistoryEntry{
+		HistoryEntry {
+			number: 0,
+			ours,
+			id,
+			author: author.to_string(),
+			body: body.to_string(),
+			edited: false,
+			deleted: false,
+			receipt: None,
+			quote: None,
+			time: None,
+			line: None,
+		}
+	}
+	//the line as it goes on screen
+	pub fn render(&self, show_receipt: bool) -> String{
+		let mut line = match self.deleted {
+			//struck through
+			true => format!("#{} ({}) \x1b[9m{}\x1b[29m (deleted)",self.number,self.author,self.body),
+			false => format!("#{} ({}) {}",self.number,self.author,self.body),
+		};
+		if self.edited && !self.deleted {
+			line += " (edited)";
+		}
+		if let Some(time) = self.time {
+			line = format!("[{}] {line}",time.format("%H:%M"));
+		}
+		if let Some(quote) = &self.quote {
+			line = format!("  > {quote}\n{line}");
+		}
+		if self.ours && show_receipt {
+			line += match self.receipt {
+				None => " [sent]",
+				Some(Receipt::Delivered) => " [delivered]",
+				Some(Receipt::Read) => " [read]",
+			};
+		}
+		line
+	}
+	//a short version for replies to show
+	pub fn quote(&self) -> String{
+		if self.deleted {
+			return format!("#{} ({}) (deleted)",self.number,self.author);
+		}
+		//colours would be cut off half way
+		let plain = plain_text(&self.body);
+		let mut body: String = plain.chars().take(QUOTE_LENGTH).collect();
+		if body.len() < plain.len() {
+			body += "…";
+		}
+		format!("#{} ({}) {body}",self.number,self.author)
+	}
+}
+
+impl History {
+	pub fn new() -> History{
+		History {entries: VecDeque::new(), next_number: 1}
+	}
+	//numbers the entry and stores it, returning it so its line can be filled in
+	pub fn push(&mut self, mut entry: HistoryEntry) -> &mut HistoryEntry{
+		entry.number = self.next_number;
+		self.next_number += 1;
+		if self.entries.len() == HISTORY_LENGTH {
+			self.entries.pop_front();
+		}
+		self.entries.push_back(entry);
+		self.entries.back_mut().unwrap()
+	}
+	pub fn get(&mut self, number: usize) -> Option<&mut HistoryEntry>{
+		self.entries.iter_mut().find(|entry| entry.number == number)
+	}
+	//find a message by who sent it and their id for it
+	pub fn find(&mut self, ours: bool, id: u64) -> Option<&mut HistoryEntry>{
+		self.entries.iter_mut().find(|entry| entry.ours == ours && entry.id == id)
+	}
+	pub fn last_ours(&mut self) -> Option<&mut HistoryEntry>{
+		self.entries.iter_mut().rev().find(|entry| entry.ours && !entry.deleted)
+	}
+	//our messages the peer never acknowledged
+	pub fn undelivered(&self) -> impl Iterator<Item = &HistoryEntry>{
+		self.entries.iter().filter(|entry| entry.ours && !entry.deleted && entry.receipt.is_none())
+	}
+}
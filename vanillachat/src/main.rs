@@ -4,34 +4,72 @@ use chrono::{DateTime,Local};
 use std::env;
 use std::os::fd::AsRawFd;
 use std::io;
-use std::io::{Read,Write,ErrorKind};
+use std::io::{Write,Read,ErrorKind};
 use std::thread;
 use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
 use std::cell::RefCell;
 use std::net::{TcpStream,TcpListener,SocketAddr,Shutdown,ToSocketAddrs};
 use nix::poll::{poll,PollFd,PollFlags};
-use nix::unistd::gethostname;
+use nix::unistd::{gethostname,read};
+use nix::libc;
+use unicode_width::UnicodeWidthStr;
 use std::os::fd::{AsFd,FromRawFd};
 use std::os::unix::net::{SocketAncillary,UnixStream,AncillaryData};
+use vanillaproto::{FrameBuffer,FrameType,Hello,Capabilities,Config,Message,Receipt,Reply,Session,Handshake,Keypair,SealedWriter,TYPING_REFRESH,TYPING_EXPIRY,exchange_hello,check_name,handshake,passphrase_key,KnownPeers,Trust,load_identity,fingerprint,short_authentication_string,socket_path,Request,Response,Pending,read_response,write_request};
+use history::{History,HistoryEntry};
+use sanitise::{clean_text,plain_text};
+
+mod history;
+mod sanitise;
 
 pub struct ThreadedIO {
 	io_lock: Mutex<()>,
 	input_buffer: Mutex<RefCell<Vec<char>>>,
 	current_prompt_state: Mutex<RefCell<String>>,
+	//how many terminal rows the prompt took up when it was last drawn
+	prompt_rows: Mutex<usize>,
+	//bytes read past the end of the last line
+	unread_input: Mutex<Vec<u8>>,
+	//terminal rows used by everything println has written
+	rows_printed: Mutex<usize>,
+	//shown in front of the prompt, e.g. when the peer is typing
+	status: Mutex<String>,
+	//shown before the status, whether the peer's key has been checked
+	verification: Mutex<String>,
+	//told when our input buffer fills or empties
+	typing_callback: Mutex<Option<TypingCallback>>,
 	old_term_settings: Termios,
 	interupt: Mutex<bool>,
 }
 
+//called with whether our input buffer has anything in it
+type TypingCallback = Box<dyn FnMut(bool) + Send>;
+
+//where ThreadedIO::println_tracked put a line
+#[derive(Clone,Copy)]
+pub struct PrintedLine {
+	row: usize,
+	rows: usize,
+}
+
 pub struct Connection {
 	time: DateTime<Local>,
 	stream: TcpStream,
-	name: String,
+	peer: Hello,
+	//features both sides support
+	capabilities: Capabilities,
+	session: Session,
+	//anything already read off the socket that isnt a whole frame yet
+	frames: FrameBuffer,
+	//texts the daemon held for us, with when they arrived
+	backlog: Vec<(DateTime<Local>,Message)>,
+	//texts the daemon sent them for us, our ids carry on from there
+	sent: u64,
 }
 
-pub struct AvailableConnection {
-	time: DateTime<Local>,
-	name: String,
-}
+//long options that take a value, as --option=value or --option value
+const VALUE_OPTIONS: [&str; 3] = ["pick","reject","tell"];
 
 pub struct Args {
 	short: Vec<String>,
@@ -39,8 +77,6 @@ pub struct Args {
 	other: Vec<String>,
 }
 
-const SOCKET_LOCATION: &str = "/tmp/vanillachatd.socket";
-
 impl Args {
 	fn gather() -> Args{
 		let mut args = Args {
@@ -48,6 +84,8 @@ impl Args {
 			long: vec![],
 			other: vec![],
 		};
+		//a long option still waiting for its value
+		let mut wants_value: Option<String> = None;
 		for enumeration in env::args().enumerate(){ 
 			if enumeration.0 == 0 {continue};
 			let arg = enumeration.1.clone();
@@ -59,9 +97,16 @@ impl Args {
 				);
 				break;
 			} 
-			if arg.len() >= 2 && arg[..2] == *"--"{
+			if let Some(option) = wants_value.take() {
+				//stored the same as --option=value
+				args.long.push(format!("{option}={arg}"));
+			}else if arg.len() >= 2 && arg[..2] == *"--"{
 				//long
-				args.long.push(arg[2..].to_string());
+				let option = arg[2..].to_string();
+				match VALUE_OPTIONS.contains(&option.as_str()) {
+					true => wants_value = Some(option),
+					false => args.long.push(option),
+				}
 			}else if arg.len() >= 1 && arg[..1] == *"-" && arg.len() != 1{
 				//short
 				args.short.extend(arg[1..].to_string().chars().map(|ch| ch.to_string()));
@@ -70,6 +115,10 @@ impl Args {
 				args.other.push(arg);
 			}
 		}
+		//left without a value, main complains about it
+		if let Some(option) = wants_value {
+			args.long.push(option);
+		}
 		//println!("short: {:?}",args.short);
 		//println!("long: {:?}",args.long);
 		//println!("other: {:?}",args.other);
@@ -83,6 +132,12 @@ impl ThreadedIO {
 			io_lock: Mutex::new(()),
 			input_buffer: Mutex::new(RefCell::new(vec![])),
 			current_prompt_state: Mutex::new(RefCell::new("".to_string())),
+			prompt_rows: Mutex::new(1),
+			unread_input: Mutex::new(vec![]),
+			rows_printed: Mutex::new(0),
+			status: Mutex::new(String::new()),
+			verification: Mutex::new(String::new()),
+			typing_callback: Mutex::new(None),
 			old_term_settings: Termios::from_fd(io::stdin().as_raw_fd()).unwrap(),
 			interupt: Mutex::new(false),
 		};
@@ -96,16 +151,46 @@ impl ThreadedIO {
 		instance
 	}
 	fn println(&self,string: String) -> Result<(),std::io::Error>{
+		self.println_tracked(string)?;
+		Ok(())
+	}
+	//same as println, but returns where the line went so it can be rewritten later
+	fn println_tracked(&self,string: String) -> io::Result<PrintedLine>{
 		let _io_guard = self.io_lock.lock();
 		let current_prompt_state_binding = self.current_prompt_state.lock().unwrap();
 		let current_prompt_state = current_prompt_state_binding.borrow();
 		let mut stdout = io::stdout();
 		//delete old prompt and insert line
-		stdout.write_all(format!("\r\x1b[2K{}\n",string).as_bytes())?;
+		stdout.write_all(format!("{}{}\n",self.clear_prompt(),string).as_bytes())?;
 		//redisplay the prompt
-		stdout.write_all(current_prompt_state.as_bytes())?;
+		stdout.write_all(self.prompt_line(&current_prompt_state).as_bytes())?;
 		stdout.flush()?;
-		Ok(())
+		//====== remember where it went ======
+		let mut rows_printed = self.rows_printed.lock().unwrap();
+		let line = PrintedLine {row: *rows_printed, rows: rows_for_text(&string)};
+		*rows_printed += line.rows;
+		Ok(line)
+	}
+	//replaces a line printed earlier, returns false if it has scrolled away or the new text wont fit
+	fn rewrite_line(&self,line: &PrintedLine,string: String) -> io::Result<bool>{
+		let _io_guard = self.io_lock.lock();
+		let rows_printed = *self.rows_printed.lock().unwrap();
+		let prompt_rows = *self.prompt_rows.lock().unwrap();
+		//rows between the start of the line and the bottom of the prompt
+		let distance = rows_printed - line.row + prompt_rows - 1;
+		if distance >= terminal_height() || rows_for_text(&string) > line.rows {
+			return Ok(false);
+		}
+		let mut output = format!("\x1b7\r\x1b[{distance}A");
+		//blank every row the old line used, then go back up and write the new one
+		for _ in 0..line.rows {
+			output += "\x1b[2K\x1b[1B";
+		}
+		output += &format!("\x1b[{}A{}\x1b8",line.rows,string);
+		let mut stdout = io::stdout();
+		stdout.write_all(output.as_bytes())?;
+		stdout.flush()?;
+		Ok(true)
 	}
 	fn input(&self,prompt: &str) -> Result<String,std::io::Error>{
 		{//reset interupt
@@ -114,51 +199,85 @@ impl ThreadedIO {
 
 		let input_buffer_binding = self.input_buffer.lock().unwrap();
 		let mut input_buffer = input_buffer_binding.borrow_mut();
-		{//====== initialy display the prompt ======
-			let _io_guard = self.io_lock.lock();
-			let current_prompt_state_binding = self.current_prompt_state.lock().unwrap();
-			let mut current_prompt_state = current_prompt_state_binding.borrow_mut();
-			*current_prompt_state = prompt.to_string() + &input_buffer.iter().collect::<String>();
-			let mut stdout = io::stdout();
-			stdout.write_all(format!("\r\x1b[2K{}",current_prompt_state).as_bytes())?;
-			stdout.flush()?;
-		}
+		//====== initialy display the prompt ======
+		self.display_prompt(prompt.to_string() + &input_buffer.iter().collect::<String>())?;
+		//when we last told the peer we are typing, None if we havent
+		let mut typing: Option<Instant> = None;
 		//====== poll wrapper that allows interuption ======
-		let wait_for_stdin = move |timeout|{
+		let wait_for_stdin = move |timeout, typing: &mut Option<Instant>|{
 			let stdin = io::stdin();
 			let mut pollfd = [PollFd::new(stdin.as_fd(),PollFlags::POLLIN)];
 			//====== wait for data ======
 			loop {
 				if poll::<u16>(&mut pollfd,timeout)? >= 1 {break}
 				if* self.interupt.lock().expect("Mutex poisoned: fatal") == true {return Err(io::Error::from(ErrorKind::Interrupted))}
+				//keep the peer's indicator up while text sits in the buffer
+				if let Some(since) = typing && since.elapsed() >= TYPING_REFRESH {
+					self.notify_typing(true);
+					*since = Instant::now();
+				}
 			}
 			io::Result::<()>::Ok(())
 		};
 		//====== get input bytes ======
-		wait_for_stdin(50)?;
-		for ch in io::stdin().bytes(){
-			match ch?{
-				10 => break,//enter
-				127 => {input_buffer.pop(); ()}, //delete
-				ch => {
-					if ch >= 32 && ch <= 126{
-						input_buffer.push(char::from(ch));
-					}else{
+		//start with anything left over from a pasted line
+		let mut pending = std::mem::take(&mut *self.unread_input.lock().unwrap());
+		//bytes of a multi byte character that hasnt fully arrived yet
+		let mut partial_char: Vec<u8> = vec![];
+		let mut read_buffer = [0; 256];
+		'reading: loop {
+			if pending.is_empty(){
+				//====== wait for data ======
+				wait_for_stdin(50,&mut typing)?;
+				//read straight from the fd so nothing gets stuck in a buffer poll cant see
+				let count = read(io::stdin().as_fd(),&mut read_buffer)?;
+				if count == 0 {return Err(io::Error::from(ErrorKind::UnexpectedEof))}
+				pending.extend_from_slice(&read_buffer[..count]);
+			}
+			for (i,ch) in pending.iter().enumerate(){
+				match *ch{
+					10 => {//enter
+						//keep the rest of a paste for the next line
+						*self.unread_input.lock().unwrap() = pending[i+1..].to_vec();
+						break 'reading;
+					},
+					8 | 127 => {partial_char.clear(); input_buffer.pop();}, //delete
+					ch if ch < 32 => {
 						//self.println(format!("unknown char {}",ch))?;
-					}
-				},
+					},
+					ch => {
+						partial_char.push(ch);
+						match std::str::from_utf8(&partial_char){
+							Ok(complete) => {
+								input_buffer.extend(complete.chars());
+								partial_char.clear();
+							},
+							//not valid utf-8, throw it away
+							Err(e) if e.error_len().is_some() => partial_char.clear(),
+							//wait for the rest of the character
+							Err(_) => (),
+						}
+					},
+				}
 			}
-			{//====== display the prompt closure ======
-				let _io_guard = self.io_lock.lock();
-				let current_prompt_state_binding = self.current_prompt_state.lock().unwrap();
-				let mut current_prompt_state = current_prompt_state_binding.borrow_mut();
-				*current_prompt_state = prompt.to_string() + &input_buffer.iter().collect::<String>();
-				let mut stdout = io::stdout();
-				stdout.write_all(format!("\r\x1b[2K{}",current_prompt_state).as_bytes())?;
-				stdout.flush()?;
+			pending.clear();
+			//====== tell the peer if we started or stopped typing ======
+			match (input_buffer.is_empty(),typing) {
+				(false,None) => {
+					self.notify_typing(true);
+					typing = Some(Instant::now());
+				},
+				(true,Some(_)) => {
+					self.notify_typing(false);
+					typing = None;
+				},
+				_ => (),
 			}
-			//====== wait for data ======
-			wait_for_stdin(50)?;
+			//====== display the prompt ======
+			self.display_prompt(prompt.to_string() + &input_buffer.iter().collect::<String>())?;
+		}
+		if typing.is_some(){
+			self.notify_typing(false);
 		}
 		{//====== clear the input buffer ======
 			let _io_guard = self.io_lock.lock();
@@ -170,6 +289,55 @@ impl ThreadedIO {
 		input_buffer.truncate(0);
 		Ok(message)
 	}
+	fn display_prompt(&self,prompt_state: String) -> io::Result<()>{
+		let _io_guard = self.io_lock.lock();
+		let current_prompt_state_binding = self.current_prompt_state.lock().unwrap();
+		let mut current_prompt_state = current_prompt_state_binding.borrow_mut();
+		*current_prompt_state = prompt_state;
+		let mut stdout = io::stdout();
+		stdout.write_all(format!("{}{}",self.clear_prompt(),self.prompt_line(&current_prompt_state)).as_bytes())?;
+		stdout.flush()
+	}
+	//shows text in front of the prompt, an empty string removes it
+	fn set_status(&self,status: String) -> io::Result<()>{
+		self.change_prompt(|| *self.status.lock().unwrap() = status)
+	}
+	//shows how far the peer's key is trusted, in front of everything else
+	fn set_verification(&self,verification: String) -> io::Result<()>{
+		self.change_prompt(|| *self.verification.lock().unwrap() = verification)
+	}
+	//redraws the prompt around a change to what goes in front of it
+	fn change_prompt(&self,change: impl FnOnce()) -> io::Result<()>{
+		let _io_guard = self.io_lock.lock();
+		let current_prompt_state_binding = self.current_prompt_state.lock().unwrap();
+		let current_prompt_state = current_prompt_state_binding.borrow();
+		let clear = self.clear_prompt();
+		change();
+		let mut stdout = io::stdout();
+		stdout.write_all(format!("{}{}",clear,self.prompt_line(&current_prompt_state)).as_bytes())?;
+		stdout.flush()
+	}
+	//the prompt as it goes on screen, call after clear_prompt as it records the new size
+	fn prompt_line(&self,current_prompt_state: &str) -> String{
+		let line = format!("{}{}{}",self.verification.lock().unwrap(),self.status.lock().unwrap(),current_prompt_state);
+		*self.prompt_rows.lock().unwrap() = rows_for_width(line.width());
+		line
+	}
+	fn on_typing(&self,callback: TypingCallback){
+		*self.typing_callback.lock().unwrap() = Some(callback);
+	}
+	fn notify_typing(&self,typing: bool){
+		if let Some(callback) = self.typing_callback.lock().unwrap().as_mut(){
+			callback(typing);
+		}
+	}
+	//escape sequence that returns to the start of the prompt and erases it, even once it has wrapped
+	fn clear_prompt(&self) -> String{
+		match *self.prompt_rows.lock().unwrap(){
+			0 | 1 => "\r\x1b[J".to_string(),
+			rows => format!("\r\x1b[{}A\x1b[J",rows-1),
+		}
+	}
 	fn interupt_input(&self){
 		let mut lock = self.interupt.lock().unwrap();
 		*lock = true;
@@ -190,10 +358,35 @@ fn main() -> Result<(),io::Error>{
 	let args = Args::gather();
 	let connection: Connection;
 	let our_name: String = gethostname()?.into_string().unwrap_or("Unknown name".into());
+	let mut our_hello = Hello::new(&our_name,env!("CARGO_PKG_VERSION"));
+	let config = Config::load()?;
+	//so they dont send us anything longer than we take
+	our_hello.max_message_size = config.max_message_size.try_into().unwrap_or(u32::MAX);
+	let keypair = load_identity()?;
 	if args.long.contains(&"help".to_string()) || args.short.contains(&"h".to_string()){
 		print_help();
 		return Ok(());
 	}
+	if let Some(option) = args.long.iter().find(|arg| VALUE_OPTIONS.contains(&arg.as_str())) {
+		print_help();
+		return Err(io::Error::new(ErrorKind::InvalidInput,format!("--{option} needs a caller's number or name.")));
+	}
+	//====== only let in someone who knows the passphrase ======
+	let passphrase = match args.long.contains(&"passphrase".to_string()) {
+		true => Some(read_passphrase()?),
+		false => None,
+	};
+	if passphrase.is_some() {
+		//so the other side knows to expect it
+		our_hello.capabilities = our_hello.capabilities.union(Capabilities::PASSPHRASE);
+	}
+	let passphrase = passphrase.as_deref();
+	//====== talk to the daemon without picking up ======
+	if let Some(result) = daemon_command(&args) {
+		return result;
+	}
+	//which of the daemon's callers to pick up
+	let pick = args.long.iter().find_map(|arg| arg.strip_prefix("pick="));
 	if args.short.contains(&"s".to_string()) || args.long.contains(&"server".to_string()){
 		//------ hosting ------
 		if args.other.len() > 1{
@@ -207,10 +400,15 @@ fn main() -> Result<(),io::Error>{
 				Err(e) => {eprintln!("Failed to parse port."); return Err(io::Error::new(ErrorKind::Other,format!("{:?}",e)))},
 			};
 		}
-		connection = socket_from_listen_addr(port,&our_name)?
+		connection = socket_from_listen_addr(port,&our_hello,&keypair,passphrase,&config)?
 	}else if args.short.contains(&"i".to_string()) || args.long.contains(&"interactive".to_string()){
 		//------ interactively get arguments ------
-		connection = socket_from_daemon() //see if there is a connection available
+		//the daemon's callers never gave a passphrase
+		let from_daemon = match passphrase {
+			Some(_) => Err(io::Error::other("Passphrase needed")),
+			None => socket_from_daemon(&our_hello,&config,pick),
+		};
+		connection = from_daemon //see if there is a connection available
 			.or_else(|_|{
 				println!("No users already connected.");
 				loop {
@@ -226,18 +424,22 @@ fn main() -> Result<(),io::Error>{
 						break Err(io::Error::other("Nothing to connect to"));
 					}
 					//only continue if connection was successfull
-					match socket_from_addr(address.trim().to_string(),port,&our_name){
+					match socket_from_addr(address.trim().to_string(),port,&our_hello,&keypair,passphrase,&config){
 						Ok(c) => break Ok(c),
-						Err(e) => eprintln!("Connection error [{e}], Try entering the address again."),
+						//the reason can be whatever the peer put in an error frame
+						Err(e) => eprintln!("Connection error [{}], Try entering the address again.",plain_text(&e.to_string())),
 					}
 				}
 			})?;
 	}else{
 		//------ connecting ------
 		if args.other.len() == 0{
+			if passphrase.is_some() {
+				return Err(io::Error::new(ErrorKind::InvalidInput,"Callers waiting in the daemon cant use a passphrase, give an address to connect to."));
+			}
 			println!("using daemon's connections...");
 			//get connection from socket
-			connection = socket_from_daemon()?;
+			connection = socket_from_daemon(&our_hello,&config,pick)?;
 		}else if args.other.len() > 2{
 			//too many arguments!!!!
 			print_help();
@@ -245,7 +447,7 @@ fn main() -> Result<(),io::Error>{
 		}else if args.other.len() == 1{
 			//address only
 			address = args.other[0].clone();
-			connection = socket_from_addr(address,port,&our_name)?;
+			connection = socket_from_addr(address,port,&our_hello,&keypair,passphrase,&config)?;
 		}else{
 			//address and port provided
 			address = args.other[0].clone();
@@ -253,35 +455,247 @@ fn main() -> Result<(),io::Error>{
 				Ok(p) => p,
 				Err(e) => {eprintln!("Failed to parse port."); return Err(io::Error::new(ErrorKind::Other,format!("{:?}",e)))},
 			};
-			connection = socket_from_addr(address,port,&our_name)?;
+			connection = socket_from_addr(address,port,&our_hello,&keypair,passphrase,&config)?;
 		}
 	}
 	//====== extract the connection details ======
-	let client_name = connection.name;
-	let mut socket = connection.stream;
+	//what they are pinned under, /nick only changes what we show
+	let peer_name = connection.peer.name.clone();
+	//anything we show of theirs has its escape codes taken out first
+	let mut client_name = plain_text(&connection.peer.name);
+	let socket = connection.stream;
+	let Session {sending,mut receiving,..} = connection.session;
+	let receipts = connection.capabilities.contains(Capabilities::RECEIPTS);
+	let typing_indicators = connection.capabilities.contains(Capabilities::TYPING);
+	let heartbeat = connection.capabilities.contains(Capabilities::HEARTBEAT);
+	let editing = connection.capabilities.contains(Capabilities::EDIT);
+	let replies = connection.capabilities.contains(Capabilities::REPLIES);
+	let verification = connection.capabilities.contains(Capabilities::VERIFY);
+	//the shorter of their limit and ours
+	let max_message_size = our_hello.max_message_size(&connection.peer);
+	let max_name_length = config.max_name_length;
 	println!("Connected!");
 	println!("client has set their name to <{}>",client_name);
+	println!("they are running vanillachat {}, shared features: {}",plain_text(&connection.peer.software_version),connection.capabilities);
+	//====== make sure they are who they were last time ======
+	let their_key = connection.session.remote_static.clone();
+	let code = short_authentication_string(&connection.session.handshake_hash);
+	let mut known_peers = KnownPeers::load()?;
+	for warning in known_peers.warnings(){
+		println!("{warning}");
+	}
+	let trust = known_peers.check(&peer_name,&their_key);
+	match trust {
+		Trust::New => {
+			known_peers.pin(&peer_name,&their_key)?;
+			println!("first time talking to <{client_name}>, their key has been remembered");
+		},
+		Trust::Known | Trust::Verified => (),
+		_ => println!("{}",trust.warning(&client_name,&their_key).unwrap_or_default()),
+	}
+	println!("their key fingerprint: {}",fingerprint(&their_key));
 	//====== init threads ======
 	let threaded_io_instance = ThreadedIO::new();
 	let receiving_thread: thread::JoinHandle<io::Result<()>>;
 	let sending_thread: thread::JoinHandle<io::Result<()>>;
 	let io_controller = Arc::new(threaded_io_instance);
+	io_controller.set_verification(verification_badge(&trust))?;
 	let continue_status = Arc::new(Mutex::new(true));
-	//let socket: TcpStream = ;
+	//both threads send, so writes go through one lock to keep frames whole
+	let writer = Arc::new(Mutex::new(SealedWriter::new(socket.try_clone()?,sending)));
+	//recent messages from both of us, for receipts and edits
+	let history = Arc::new(Mutex::new(History::new()));
+	if typing_indicators {
+		let writer = writer.clone();
+		io_controller.on_typing(Box::new(move |typing|{
+			//if this fails the receiving thread will notice the connection is gone
+			let _ = send_message(&writer,&Message::Typing {typing});
+		}));
+	}
+	//====== show what they said while we were on hold ======
+	if !connection.backlog.is_empty() {
+		io_controller.println(format!("while you were away <{client_name}> said:"))?;
+		let mut history = history.lock().unwrap();
+		for (time,message) in connection.backlog{
+			let Message::Text {id,body,reply_to} = message else {continue};
+			let quote = reply_to.map(|reply| match history.find(!reply.own,reply.id) {
+				Some(quoted) => quoted.quote(),
+				None => "an older message".to_string(),
+			});
+			let entry = history.push(HistoryEntry::new(false,id,&client_name,&clean_text(&body)));
+			entry.quote = quote;
+			entry.time = Some(time);
+			entry.line = Some(io_controller.println_tracked(entry.render(receipts))?);
+			if receipts {
+				send_message(&writer,&Message::Ack {id, receipt: Receipt::Read})?;
+			}
+		}
+	}
 	{//====== receiving messages thread ======
 		let continue_status = continue_status.clone();
 		let io = io_controller.clone();
-		let mut socket = socket.try_clone()?;
+		let writer = writer.clone();
+		let history = history.clone();
+		let code = code.clone();
+		let their_key = their_key.clone();
 		receiving_thread = thread::spawn(move ||{
+			let mut frames = connection.frames;
+			frames.set_max_payload(config.max_frame_payload());
+			//when the peer's typing indicator should go away
+			let mut typing_until: Option<Instant> = None;
+			let mut last_heard = Instant::now();
+			let mut last_ping = Instant::now();
 			match loop {//====== mainloop ======
-				let message = match recv_msg(&mut socket){
-					Ok(m) => m,
+				//====== timers ======
+				if let Some(until) = typing_until && Instant::now() >= until {
+					typing_until = None;
+					if let Err(e) = io.set_status(String::new()) {break Err(e)}
+				}
+				if heartbeat {
+					if last_heard.elapsed() >= config.heartbeat_timeout {
+						let e = io::Error::new(ErrorKind::TimedOut,format!("Peer stopped responding, nothing heard for {}s.",last_heard.elapsed().as_secs()));
+						io.println(format!("Connection error: {:?}",e))?;
+						//in case they can still hear us
+						let _ = send_message(&writer,&Message::Goodbye {reason: "timeout".into()});
+						break Err(e)
+					}
+					if last_ping.elapsed() >= config.heartbeat_interval {
+						last_ping = Instant::now();
+						if let Err(e) = send_message(&writer,&Message::Ping) {break Err(e)}
+					}
+				}
+				//wake up regularly so the timers above get a look in
+				let message = match frames.read_from(&socket,Duration::from_millis(500))
+					.and_then(|frame| frame.map(|frame| receiving.open(&frame).and_then(|frame| Message::decode(&frame))).transpose()){
+					Ok(Some(m)) => {
+						last_heard = Instant::now();
+						m
+					},
+					Ok(None) => continue,
+					//we shut the socket ourselves with /exit
+					Err(_) if !*continue_status.lock().unwrap() => break Ok(()),
 					Err(e) => {
+						//tell them what they did wrong, we cant find the next frame after it
+						if e.kind() == ErrorKind::InvalidData {
+							let _ = writer.lock().unwrap().write_frame(FrameType::Error,e.to_string().as_bytes());
+						}
 						let _ = io.println(format!("Connection error: {:?}",e))?;
 						break Err(e)
 					},
 				};
-				let _ = match io.println(format!("({client_name}) {message}")){
+				//====== dispatch on the message type ======
+				let line = match message {
+					Message::Text {id,body,reply_to} => {
+						let body = clean_text(&body);
+						if receipts && let Err(e) = send_message(&writer,&Message::Ack {id, receipt: Receipt::Delivered}){
+							break Err(e)
+						}
+						//they have stopped typing, they sent it
+						if typing_until.take().is_some() && let Err(e) = io.set_status(String::new()){
+							break Err(e)
+						}
+						let mut history = history.lock().unwrap();
+						//own is from their side, so their messages are the ones that arent ours
+						let quote = reply_to.map(|reply| match history.find(!reply.own,reply.id) {
+							Some(quoted) => quoted.quote(),
+							None => "an older message".to_string(),
+						});
+						let entry = history.push(HistoryEntry::new(false,id,&client_name,&body));
+						entry.quote = quote;
+						entry.line = match io.println_tracked(entry.render(receipts)){
+							Ok(line) => Some(line),
+							Err(e) => break Err(e),
+						};
+						drop(history);
+						//====== its on their screen now ======
+						if receipts && let Err(e) = send_message(&writer,&Message::Ack {id, receipt: Receipt::Read}){
+							break Err(e)
+						}
+						continue;
+					},
+					Message::Nick {name} => {
+						if let Some(reason) = check_name(&name,max_name_length) {
+							let _ = writer.lock().unwrap().write_frame(FrameType::Error,reason.as_bytes());
+							let e = io::Error::new(ErrorKind::InvalidData,reason);
+							io.println(format!("Connection error: {:?}",e))?;
+							break Err(e)
+						}
+						let line = format!("<{client_name}> is now known as <{}>",plain_text(&name));
+						client_name = plain_text(&name);
+						//the new name may be pinned to someone else's key
+						match KnownPeers::load().map(|known_peers| known_peers.check(&name,&their_key)) {
+							Ok(trust) => match trust.warning(&client_name,&their_key) {
+								Some(warning) => {
+									if let Err(e) = io.set_verification(verification_badge(&trust)) {break Err(e)}
+									format!("{line}\n{warning}")
+								},
+								None => line,
+							},
+							Err(e) => format!("{line}\ncould not check the key pinned for <{client_name}>: {e}"),
+						}
+					},
+					Message::Ack {id,receipt} => {
+						let mut history = history.lock().unwrap();
+						if let Some(entry) = history.find(true,id) && entry.receipt < Some(receipt){
+							entry.receipt = Some(receipt);
+							if let Err(e) = redisplay(&io,entry,receipts,None) {break Err(e)}
+						}
+						continue;
+					},
+					Message::Edit {id,body} => {
+						let mut history = history.lock().unwrap();
+						//too old to remember, nothing to change
+						let Some(entry) = history.find(false,id) else {continue};
+						entry.body = clean_text(&body);
+						entry.edited = true;
+						let notice = format!("<{client_name}> edited #{}: {}",entry.number,entry.body);
+						if let Err(e) = redisplay(&io,entry,receipts,Some(notice)) {break Err(e)}
+						continue;
+					},
+					Message::Delete {id} => {
+						let mut history = history.lock().unwrap();
+						let Some(entry) = history.find(false,id) else {continue};
+						entry.deleted = true;
+						let notice = format!("<{client_name}> deleted #{}",entry.number);
+						if let Err(e) = redisplay(&io,entry,receipts,Some(notice)) {break Err(e)}
+						continue;
+					},
+					Message::Verify {confirmed: false} => format!(
+						"<{client_name}> wants to check nobody is listening in, compare this code with them out loud:\n{code}\nif it matches, type /verify confirm"
+					),
+					Message::Verify {confirmed: true} => format!("<{client_name}> confirmed the code matches"),
+					Message::Typing {typing} => {
+						let was_typing = typing_until.is_some();
+						typing_until = if typing {Some(Instant::now() + TYPING_EXPIRY)} else {None};
+						let status = match typing {
+							true if !was_typing => Some(format!("[<{client_name}> is typing…] ")),
+							false if was_typing => Some(String::new()),
+							_ => None,
+						};
+						if let Some(status) = status && let Err(e) = io.set_status(status){
+							break Err(e)
+						}
+						continue;
+					},
+					Message::Ping => {
+						if let Err(e) = send_message(&writer,&Message::Pong) {break Err(e)}
+						continue;
+					},
+					Message::Pong => continue,
+					Message::Goodbye {reason} => {
+						let reason = clean_text(&reason);
+						let _ = io.println(match reason.is_empty() {
+							true => format!("<{client_name}> left"),
+							false => format!("<{client_name}> left ({reason})"),
+						});
+						*continue_status.lock().unwrap() = false;
+						let _ = writer.lock().unwrap().get_ref().shutdown(Shutdown::Both);
+						break Ok(())
+					},
+					Message::System {text} => format!("* {}",clean_text(&text)),
+					Message::Unknown => continue,
+				};
+				let _ = match io.println(line){
 					Ok(()) => io::Result::Ok(()),
 					Err(e) => break Err(e),
 				};
@@ -293,13 +707,19 @@ fn main() -> Result<(),io::Error>{
 					if *keep_going == false {break Ok(())}
 				}
 			}{//====== match result from loop ======
-				Ok(()) => Ok(()),
+				Ok(()) => {
+					if receipts {report_undelivered(&io,&history)}
+					//stop waiting on input if they left
+					io.interupt_input();
+					Ok(())
+				},
 				Err(e) => {
 					let mut keep_going = match continue_status.lock(){
 						Ok(t) => t,
 						Err(e) => return Err(io::Error::other(format!("{:?}",e)))
 					};
 					*keep_going = false;
+					if receipts {report_undelivered(&io,&history)}
 					io.interupt_input();
 					Err(e)
 				},
@@ -309,28 +729,159 @@ fn main() -> Result<(),io::Error>{
 	{//====== input handling thread ======
 		let continue_status = continue_status.clone();
 		let io = io_controller.clone();
+		let mut our_name = our_name;
+		let mut next_id: u64 = connection.sent + 1;
+		let history = history.clone();
 		sending_thread = thread::spawn(move ||{
 			match loop {//====== mainloop ======
 				//get the message
 				let message = match io.input(">>>"){
 					Ok(m) => m,
+					//the peer said goodbye
+					Err(ref e) if e.kind() == ErrorKind::Interrupted && !*continue_status.lock().unwrap() => break Ok(()),
 					Err(e) => break Err(e),
 				};
+				//dont send anything they will refuse
+				if message.len() > max_message_size {
+					io.println(format!("That is {} bytes long, the most you can send is {max_message_size}.",message.len()))?;
+					continue;
+				}
 				//exit
-				if message == "/exit" {
+				if message == "/exit" || message.starts_with("/exit ") {
 					let mut keep_going = match continue_status.lock(){
 						Ok(t) => t,
 						Err(e) => return Err(io::Error::other(format!("{:?}",e)))
 					};
 					//stop cleanly
 					*keep_going = false;
+					//let them know we are going
+					let reason = match message["/exit".len()..].trim() {
+						"" => "user left".to_string(),
+						reason => reason.to_string(),
+					};
+					let _ = send_message(&writer,&Message::Goodbye {reason});
 					//kill the socket so we dont hang on recv
-					let _ = socket.shutdown(Shutdown::Both);
+					let _ = writer.lock().unwrap().get_ref().shutdown(Shutdown::Both);
 					//exit
 					break Ok(());
 				}
+				//change our name
+				if let Some(name) = message.strip_prefix("/nick "){
+					let name = name.trim().to_string();
+					if let Some(reason) = check_name(&name,max_name_length) {
+						io.println(reason)?;
+						continue;
+					}
+					if let Err(e) = send_message(&writer,&Message::Nick {name: name.clone()}){
+						io.println(format!("Connection error: {:?}",e))?;
+						break Err(e)
+					}
+					io.println(format!("you are now known as <{name}>"))?;
+					our_name = name;
+					continue;
+				}
+				//====== edit or delete one of ours ======
+				let edit = command_arguments(&message,"/edit");
+				let delete = command_arguments(&message,"/delete");
+				if edit.is_some() || delete.is_some() {
+					if !editing {
+						io.println("The other person cant see edits or deletions, their vanillachat is too old.".into())?;
+						continue;
+					}
+					let mut history = history.lock().unwrap();
+					let (number,body) = match (edit,delete) {
+						(Some(arguments),_) => parse_message_number(arguments),
+						(_,Some(arguments)) => (arguments.trim_start_matches('#').parse().ok(),""),
+						_ => unreachable!(),
+					};
+					let entry = match number {
+						Some(number) => history.get(number).filter(|entry| entry.ours && !entry.deleted),
+						None => history.last_ours(),
+					};
+					let Some(entry) = entry else {
+						io.println("No message of yours to change, see /help for usage.".into())?;
+						continue;
+					};
+					let (change,notice) = match edit {
+						Some(_) if body.is_empty() => {
+							io.println("Usage: /edit [#number] <new text>".into())?;
+							continue;
+						},
+						Some(_) => {
+							entry.body = body.to_string();
+							entry.edited = true;
+							(Message::Edit {id: entry.id, body: body.to_string()},format!("you edited #{}: {body}",entry.number))
+						},
+						None => {
+							entry.deleted = true;
+							(Message::Delete {id: entry.id},format!("you deleted #{}",entry.number))
+						},
+					};
+					if let Err(e) = send_message(&writer,&change){
+						io.println(format!("Connection error: {:?}",e))?;
+						break Err(e)
+					}
+					redisplay(&io,entry,receipts,Some(notice))?;
+					continue;
+				}
+				//hold the lock until the echo is printed, so receipts cant arrive before we know where it is
+				let mut history = history.lock().unwrap();
+				//====== compare codes to prove nobody is in the middle ======
+				if let Some(arguments) = command_arguments(&message,"/verify") {
+					if !verification {
+						io.println("The other person cant verify keys, their vanillachat is too old.".into())?;
+						continue;
+					}
+					let confirmed = match arguments {
+						"" => false,
+						"confirm" => true,
+						_ => {
+							io.println("Usage: /verify [confirm]".into())?;
+							continue;
+						},
+					};
+					if confirmed {
+						//a problem with the file shouldnt end the chat
+						if let Err(e) = KnownPeers::load().and_then(|mut known_peers| known_peers.verify(&peer_name,&their_key)) {
+							io.println(format!("Could not mark <{}> as verified: {e}",plain_text(&peer_name)))?;
+							continue;
+						}
+						io.set_verification(verification_badge(&Trust::Verified))?;
+						io.println(format!("<{}> is now verified",plain_text(&peer_name)))?;
+					}else{
+						io.println(format!("compare this code with <{}> out loud:\n{code}\nif it matches, type /verify confirm",plain_text(&peer_name)))?;
+					}
+					if let Err(e) = send_message(&writer,&Message::Verify {confirmed}){
+						io.println(format!("Connection error: {:?}",e))?;
+						break Err(e)
+					}
+					continue;
+				}
+				//====== quote an earlier message ======
+				let (message,reply_to,quote) = match command_arguments(&message,"/reply") {
+					Some(_) if !replies => {
+						io.println("The other person cant see replies, their vanillachat is too old.".into())?;
+						continue;
+					},
+					Some(arguments) => {
+						let (number,body) = arguments.split_once(' ').unwrap_or((arguments,""));
+						let body = body.trim();
+						let Some(number) = number.trim_start_matches('#').parse::<usize>().ok().filter(|_| !body.is_empty()) else {
+							io.println("Usage: /reply <number> <text>".into())?;
+							continue;
+						};
+						let Some(quoted) = history.get(number) else {
+							io.println(format!("There is no message #{number} to reply to."))?;
+							continue;
+						};
+						(body.to_string(),Some(Reply {own: quoted.ours, id: quoted.id}),Some(quoted.quote()))
+					},
+					None => (message,None,None),
+				};
+				let id = next_id;
+				next_id += 1;
 				//send the mesage
-				match send_msg(&mut socket,&message){
+				match send_message(&writer,&Message::Text {id, body: message.clone(), reply_to}){
 					Ok(()) => (),
 					Err(e) => {
 						let _ = io.println(format!("Connection error: {:?}",e))?;
@@ -338,7 +889,10 @@ fn main() -> Result<(),io::Error>{
 					},
 				};
 				//echo their message back to them
-				io.println(format!("({our_name}) {message}"))?;
+				let entry = history.push(HistoryEntry::new(true,id,&our_name,&message));
+				entry.quote = quote;
+				entry.line = Some(io.println_tracked(entry.render(receipts))?);
+				drop(history);
 				{//use bool to signal when to terminate thread
 					let keep_going = match continue_status.lock(){
 						Ok(t) => t,
@@ -364,6 +918,92 @@ fn main() -> Result<(),io::Error>{
 	receiving_thread.join().expect("Couldnt join threads with main")?;
 	sending_thread.join().expect("Couldnt join threads with main")
 }
+fn terminal_size() -> libc::winsize{
+	let mut size = libc::winsize {ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0};
+	let result = unsafe {libc::ioctl(io::stdout().as_raw_fd(),libc::TIOCGWINSZ,&mut size)};
+	if result == -1 {libc::winsize {ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0}} else {size}
+}
+fn terminal_width() -> usize{
+	match terminal_size().ws_col {0 => 80, columns => columns as usize}
+}
+fn terminal_height() -> usize{
+	match terminal_size().ws_row {0 => 24, rows => rows as usize}
+}
+//number of rows text of a given display width takes up before the terminal wraps it
+fn rows_for_width(width: usize) -> usize{
+	let columns = terminal_width();
+	width.div_ceil(columns).max(1)
+}
+fn rows_for_text(text: &str) -> usize{
+	//escape codes take up no room on screen
+	text.split('\n').map(|line| rows_for_width(plain_text(line).width())).sum()
+}
+fn send_message(writer: &Mutex<SealedWriter<TcpStream>>, message: &Message) -> io::Result<()>{
+	writer.lock().unwrap().write_message(message)
+}
+//point out anything the peer never got
+fn report_undelivered(io: &ThreadedIO, history: &Mutex<History>){
+	for entry in history.lock().unwrap().undelivered(){
+		let _ = io.println(format!("NOT DELIVERED: {}",entry.render(false)));
+	}
+}
+//goes in front of the prompt
+fn verification_badge(trust: &Trust) -> String{
+	match trust {
+		Trust::Verified => "[verified] ".into(),
+		Trust::Changed {..} => "[KEY CHANGED] ".into(),
+		_ => "[unverified] ".into(),
+	}
+}
+//shows a changed message in place, or prints the notice if its line has gone
+fn redisplay(io: &ThreadedIO, entry: &HistoryEntry, show_receipt: bool, notice: Option<String>) -> io::Result<()>{
+	let rewritten = match entry.line {
+		Some(line) => io.rewrite_line(&line,entry.render(show_receipt))?,
+		None => false,
+	};
+	if !rewritten && let Some(notice) = notice {
+		io.println(notice)?;
+	}
+	Ok(())
+}
+//what follows a command, if the message is that command
+fn command_arguments<'a>(message: &'a str, command: &str) -> Option<&'a str>{
+	match message.strip_prefix(command) {
+		Some("") => Some(""),
+		Some(arguments) if arguments.starts_with(' ') => Some(arguments.trim()),
+		_ => None,
+	}
+}
+//splits a leading "#number" off a commands arguments
+fn parse_message_number(arguments: &str) -> (Option<usize>,&str){
+	if let Some(rest) = arguments.strip_prefix('#'){
+		let (number,rest) = rest.split_once(' ').unwrap_or((rest,""));
+		if let Ok(number) = number.parse(){
+			return (Some(number),rest.trim());
+		}
+	}
+	(None,arguments)
+}
+//asks for a passphrase without echoing it
+fn read_passphrase() -> io::Result<String>{
+	let stdin = io::stdin().as_raw_fd();
+	let old_term_settings = Termios::from_fd(stdin)?;
+	let mut term = old_term_settings;
+	term.c_lflag &= !ECHO;
+	print!("passphrase >>>");
+	io::stdout().flush()?;
+	tcsetattr(stdin,TCSANOW,&term)?;
+	let mut passphrase = String::new();
+	let result = io::stdin().read_line(&mut passphrase);
+	tcsetattr(stdin,TCSANOW,&old_term_settings)?;
+	println!();
+	result?;
+	let passphrase = passphrase.trim_end_matches(['\r','\n']).to_string();
+	if passphrase.is_empty() {
+		return Err(io::Error::new(ErrorKind::InvalidInput,"The passphrase cant be empty."));
+	}
+	Ok(passphrase)
+}
 fn print_help(){
 	let name = env::args().next().unwrap();
 	println!("help:");
@@ -371,102 +1011,206 @@ fn print_help(){
 	println!("{} [options] to connect through the daemon",name);
 	println!("for hosting:");
 	println!("{} [options] <\"-s\" or \"--server\"> [port]",name);
+	println!("options:");
+	println!("--passphrase - asks for a passphrase, the other person has to use --passphrase with the same one to connect.");
+	println!("--pick <number or name> - which caller to pick up when connecting through the daemon, otherwise you are asked if there are several.");
+	println!("talking to the daemon without picking up:");
+	println!("--status - shows how the daemon is doing and who is waiting.");
+	println!("--reject <number or name> [reason] - hangs up on a caller.");
+	println!("--tell <number or name> <text> - sends a caller a message while they wait.");
+	println!("--stop-daemon - hangs up on everyone and stops the daemon.");
 	println!("commands:");
 	println!("commands start with '/'");
-	println!("/exit - exits properly and cleans up the terminal settings. if not used, may result in your terminal behaving weirdly.");
-}
-fn socket_from_daemon() -> io::Result<Connection>{
-	let mut daemon = UnixStream::connect(SOCKET_LOCATION)?;
-	//====== receive list of available connections ======
-	let mut count_buffer = [0; 4];
-	daemon.read_exact(&mut count_buffer)?;
-	let connection_count = u32::from_be_bytes(count_buffer);
-	let mut connections = vec![];
-	for _i in 0..connection_count{
-		//read timestamp of when connection was made
-		let mut timestamp_buffer = [0; 8];
-		daemon.read_exact(&mut timestamp_buffer)?;
-		let timestamp = u64::from_be_bytes(timestamp_buffer);
-		//push the connection
-		connections.push(AvailableConnection {
-			//if extracting the date fails, fallback to unix epoch
-			time: DateTime::from_timestamp(timestamp as i64,0).unwrap_or(DateTime::UNIX_EPOCH).into(),
-			//read the name they provide
-			name: recv_msg(&mut daemon)?,
-		});
-
+	println!("/exit [reason] - exits properly and cleans up the terminal settings. if not used, may result in your terminal behaving weirdly.");
+	println!("/nick <name> - changes the name the other person sees you as.");
+	println!("/edit [#number] <new text> - changes your last message, or the one with that number.");
+	println!("/delete [number] - deletes your last message, or the one with that number.");
+	println!("/reply <number> <text> - sends a message quoting the one with that number.");
+	println!("/verify [confirm] - shows a code to compare with the other person, confirm marks them as verified if it matches.");
+}
+//sends the daemon one request and reads its answer
+fn ask_daemon(request: &Request) -> io::Result<(UnixStream,Response)>{
+	let mut daemon = UnixStream::connect(socket_path()?)?;
+	write_request(&mut daemon,request)?;
+	let response = read_response(&mut daemon)?;
+	Ok((daemon,response))
+}
+fn list_pending() -> io::Result<Vec<Pending>>{
+	match ask_daemon(&Request::ListPending)?.1 {
+		Response::Pending(callers) => Ok(callers),
+		_ => Err(io::Error::new(ErrorKind::InvalidData,"The daemon didnt answer with a list of callers.")),
 	}
-	//====== request socket ======
-	let selected_connection = 0;
-	if connection_count == 0{
-		//no socket available
-		Err(io::Error::other("No sockets available"))
+}
+//for requests that only need to know they worked
+fn tell_daemon(request: &Request) -> io::Result<()>{
+	match ask_daemon(request)?.1 {
+		Response::Done => Ok(()),
+		_ => Err(io::Error::new(ErrorKind::InvalidData,"The daemon gave an answer that doesnt fit the request.")),
+	}
+}
+//handles --status, --reject, --tell and --stop-daemon, if one was given
+fn daemon_command(args: &Args) -> Option<io::Result<()>>{
+	let reject = args.long.iter().find_map(|arg| arg.strip_prefix("reject="));
+	let tell = args.long.iter().find_map(|arg| arg.strip_prefix("tell="));
+	if args.long.contains(&"status".to_string()) {
+		Some(daemon_status())
+	}else if let Some(pick) = reject {
+		let reason = match args.other.is_empty() {
+			true => "not picking up".to_string(),
+			false => args.other.join(" "),
+		};
+		Some(list_pending().and_then(|callers| {
+			let caller = &callers[choose_caller(&callers,Some(pick))?];
+			tell_daemon(&Request::Reject {id: caller.id, reason})?;
+			println!("hung up on <{}>",plain_text(&caller.peer.name));
+			Ok(())
+		}))
+	}else if let Some(pick) = tell {
+		if args.other.is_empty() {
+			return Some(Err(io::Error::new(ErrorKind::InvalidInput,"Nothing to tell them.")));
+		}
+		Some(list_pending().and_then(|callers| {
+			let caller = &callers[choose_caller(&callers,Some(pick))?];
+			tell_daemon(&Request::SendToPending {id: caller.id, body: args.other.join(" ")})
+		}))
+	}else if args.long.contains(&"stop-daemon".to_string()) {
+		Some(tell_daemon(&Request::Shutdown))
 	}else{
-		//request
-		let mut request_buffer: [u8; 4] = u32::to_be_bytes(selected_connection);
-		daemon.write_all(&mut request_buffer)?;
-		let mut buf = [0; 128];
-		let slice_buf = io::IoSliceMut::new(&mut buf);
-		let mut ancillary_buffer = [0; 128];
-		let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
-		daemon.recv_vectored_with_ancillary(&mut [slice_buf],&mut ancillary)?;
-		//extract fds
-		for ancillary_result in ancillary.messages(){
-			if let AncillaryData::ScmRights(mut rights) = ancillary_result.unwrap(){
-				return Ok(Connection {
-					stream: unsafe {TcpStream::from_raw_fd(rights.next().expect("Couldnt find fd in ancillary data"))},
-					time: connections[0].time,
-					name: connections[0].name.clone(),
-				});
+		None
+	}
+}
+fn daemon_status() -> io::Result<()>{
+	let Response::Status(status) = ask_daemon(&Request::Status)?.1 else {
+		return Err(io::Error::new(ErrorKind::InvalidData,"The daemon didnt answer with its status."));
+	};
+	println!("vchatd {}, up for {}",status.software_version,format_duration(status.uptime));
+	println!("{} on hold, {} still connecting",status.pending,status.connecting);
+	print_callers(&list_pending()?);
+	Ok(())
+}
+//seconds, minutes or hours and minutes, whichever reads best
+fn format_duration(seconds: u64) -> String{
+	match seconds {
+		0..60 => format!("{seconds}s"),
+		60..3600 => format!("{}m",seconds / 60),
+		_ => format!("{}h{}m",seconds / 3600,seconds % 3600 / 60),
+	}
+}
+fn print_callers(callers: &[Pending]){
+	for (index,caller) in callers.iter().enumerate(){
+		let waited = (Local::now().timestamp() as u64).saturating_sub(caller.called);
+		let queued = match caller.queued {
+			1 => "1 message".to_string(),
+			queued => format!("{queued} messages"),
+		};
+		println!("{}: <{}> from {}, waiting {}, {queued}",index + 1,plain_text(&caller.peer.name),caller.address,format_duration(waited));
+	}
+}
+//picks the caller named by --pick, otherwise asks if there is more than one
+fn choose_caller(callers: &[Pending], pick: Option<&str>) -> io::Result<usize>{
+	if let Some(pick) = pick {
+		//a number from the list, or a name
+		return match pick.parse::<usize>() {
+			Ok(number) if (1..=callers.len()).contains(&number) => Ok(number - 1),
+			Ok(number) => Err(io::Error::new(ErrorKind::NotFound,format!("There is no caller {number}, only {} waiting.",callers.len()))),
+			Err(_) => callers.iter().position(|caller| plain_text(&caller.peer.name) == pick)
+				.ok_or_else(|| io::Error::new(ErrorKind::NotFound,format!("Nobody called <{pick}> is waiting."))),
+		};
+	}
+	if callers.len() == 1 {
+		return Ok(0);
+	}
+	println!("{} callers waiting:",callers.len());
+	print_callers(callers);
+	loop {
+		print!("pick a caller >>>");
+		io::stdout().flush()?;
+		let mut choice = String::new();
+		if io::stdin().read_line(&mut choice)? == 0 {
+			break Err(io::Error::from(ErrorKind::UnexpectedEof));
+		}
+		match choice.trim().parse::<usize>() {
+			Ok(number) if (1..=callers.len()).contains(&number) => break Ok(number - 1),
+			_ => println!("Enter a number from 1 to {}.",callers.len()),
+		}
+	}
+}
+fn socket_from_daemon(our_hello: &Hello, config: &Config, pick: Option<&str>) -> io::Result<Connection>{
+	//====== see who is waiting ======
+	let mut callers = list_pending()?;
+	if callers.is_empty() {
+		//no socket available
+		return Err(io::Error::other("No sockets available"));
+	}
+	let selected = callers.swap_remove(choose_caller(&callers,pick)?);
+	//====== take over the daemon's end of the encryption ======
+	let (mut daemon,response) = ask_daemon(&Request::Take {id: selected.id})?;
+	let Response::Taken(handover) = response else {
+		return Err(io::Error::new(ErrorKind::InvalidData,"The daemon didnt hand the caller over."));
+	};
+	//exactly the "Ok" sent with the fd, so nothing after it gets swallowed
+	let mut buf = [0; 2];
+	let slice_buf = io::IoSliceMut::new(&mut buf);
+	let mut ancillary_buffer = [0; 128];
+	let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+	daemon.recv_vectored_with_ancillary(&mut [slice_buf],&mut ancillary)?;
+	//extract fds
+	for ancillary_result in ancillary.messages(){
+		if let AncillaryData::ScmRights(mut rights) = ancillary_result.unwrap(){
+			let mut stream = unsafe {TcpStream::from_raw_fd(rights.next().expect("Couldnt find fd in ancillary data"))};
+			//so the daemon lets go of them
+			daemon.write_all(b"Ok")?;
+			//and nothing touches the socket until it has, or we would both be reading it
+			let mut reply = [0; 2];
+			if daemon.read_exact(&mut reply).is_err() || &reply != b"Ok" {
+				return Err(io::Error::other("The daemon kept the caller, try again."));
+			}
+			//anything the daemon sealed but couldnt send yet comes before what we seal
+			stream.write_all(&handover.outgoing)?;
+			let session = handover.session;
+			let mut frames = FrameBuffer::new();
+			frames.extend(&handover.pending);
+			let backlog = handover.backlog.into_iter()
+				.map(|(time,message)| (DateTime::from_timestamp(time as i64,0).unwrap_or(DateTime::UNIX_EPOCH).into(),message))
+				.collect();
+			//the daemon greeted them for us, make sure we can talk to them too
+			if let Some(reason) = our_hello.incompatibility(&selected.peer).or_else(|| check_name(&selected.peer.name,config.max_name_length)) {
+				let _ = SealedWriter::new(&mut stream,session.sending.clone()).write_frame(FrameType::Error,reason.as_bytes());
+				return Err(io::Error::new(ErrorKind::InvalidData,reason));
 			}
+			return Ok(Connection {
+				stream,
+				//if extracting the date fails, fallback to unix epoch
+				time: DateTime::from_timestamp(selected.called as i64,0).unwrap_or(DateTime::UNIX_EPOCH).into(),
+				capabilities: our_hello.negotiate(&selected.peer),
+				peer: selected.peer,
+				session,
+				frames,
+				backlog,
+				sent: handover.sent,
+			});
 		}
-		Err(io::Error::other("Could not find fd in ancillary data"))
 	}
+	Err(io::Error::other("Could not find fd in ancillary data"))
 }
-fn socket_from_addr(address: String, port: u16, our_name: &String) -> io::Result<Connection>{
+fn socket_from_addr(address: String, port: u16, our_hello: &Hello, keypair: &Keypair, passphrase: Option<&str>, config: &Config) -> io::Result<Connection>{
 	let mut stream = TcpStream::connect((address,port))?;
-	//send our name
-	send_msg(&mut stream,&our_name)?;
-	//receive their name
-	let name = recv_msg(&mut stream)?;
-	Ok(Connection {stream: stream, name: name, time: Local::now()})
+	//swap hellos
+	let peer = exchange_hello(&mut stream,our_hello,config.max_name_length,config.max_handshake_payload())?;
+	let psk = passphrase.map(|passphrase| passphrase_key(&mut stream,passphrase,config.max_handshake_payload())).transpose()?;
+	//we connected, so we start the handshake
+	let session = handshake(&mut stream,Handshake::new(true,keypair,our_hello,&peer,psk.as_ref())?,config.max_handshake_payload())?;
+	Ok(Connection {stream, capabilities: our_hello.negotiate(&peer), peer, time: Local::now(), session, frames: FrameBuffer::new(), backlog: vec![], sent: 0})
 }
-fn socket_from_listen_addr(port: u16, our_name: &String) -> io::Result<Connection>{
+fn socket_from_listen_addr(port: u16, our_hello: &Hello, keypair: &Keypair, passphrase: Option<&str>, config: &Config) -> io::Result<Connection>{
 	let listener = TcpListener::bind(("0.0.0.0",port));
 	let mut stream = match listener?.accept(){
 		Ok((sock,_addr)) => Ok(sock),
 		Err(e) => Err(e),
 	}?;
-	//send our name
-	send_msg(&mut stream,&our_name)?;
-	//receive their name
-	let name = recv_msg(&mut stream)?;
-	Ok(Connection {stream: stream, name: name, time: Local::now()})
-}
-fn recv_msg<T: io::Read>(stream: &mut T) -> io::Result<String>{
-	//switch to nonblocking
-	//====== read ======
-	let mut message_buffer = vec![];
-	let mut buffer = [0; 1];
-	loop{
-		let count = match stream.read(&mut buffer){
-			Ok(count) => count,
-			Err(e) => break Err(e),
-		};
-		if count == 1 {
-			if buffer[0] == 0x04{
-				//end of transmition
-				break Ok(message_buffer.into_iter().collect());
-			}
-			//====== append the char to the buffer ======
-			let ch = char::from_u32(buffer[0] as u32).unwrap();
-			message_buffer.push(ch);
-		}else{
-			break Err(io::Error::from(ErrorKind::UnexpectedEof));
-		}
-	}
-}
-fn send_msg<T: io::Write>(stream: &mut T,message: &String) -> io::Result<()>{
-	stream.write_all((message.to_owned()+"\x04").as_bytes())?;
-	Ok(())
+	//swap hellos
+	let peer = exchange_hello(&mut stream,our_hello,config.max_name_length,config.max_handshake_payload())?;
+	let psk = passphrase.map(|passphrase| passphrase_key(&mut stream,passphrase,config.max_handshake_payload())).transpose()?;
+	let session = handshake(&mut stream,Handshake::new(false,keypair,&peer,our_hello,psk.as_ref())?,config.max_handshake_payload())?;
+	Ok(Connection {stream, capabilities: our_hello.negotiate(&peer), peer, time: Local::now(), session, frames: FrameBuffer::new(), backlog: vec![], sent: 0})
 }
@@ -0,0 +1,84 @@
+use ansi_control_codes::parser::{TokenStream,Token};
+use ansi_control_codes::c0::{BEL,HT,LF,CR};
+use ansi_control_codes::c1::{OSC,DCS,SOS,PM,APC,ST};
+
+//peer text with nothing left that could clear the screen, move the cursor,
+//retitle the window or touch the clipboard, colours are kept
+pub fn clean_text(text: &str) -> String{
+	clean(text,true)
+}
+
+//the same with the colours gone too, for names and anywhere we measure or cut text
+pub fn plain_text(text: &str) -> String{
+	clean(text,false)
+}
+
+fn clean(text: &str, keep_colours: bool) -> String{
+	let mut cleaned = String::with_capacity(text.len());
+	let mut coloured = false;
+	//control strings like OSC run until ST or BEL, and all of it goes
+	let mut in_control_string = false;
+	for token in TokenStream::from(text){
+		match token {
+			Token::ControlFunction(function) => {
+				let function = function.to_string();
+				if in_control_string {
+					in_control_string = !(function == ST || function == BEL);
+				}else if [OSC,DCS,SOS,PM,APC].into_iter().any(|start| function == start) {
+					in_control_string = true;
+				}else if [HT,LF,CR].into_iter().any(|space| function == space) {
+					//dont let words run together, but dont let them start a fake line either
+					cleaned.push(' ');
+				}else if keep_colours && function.starts_with("\x1b[") && function.ends_with('m') {
+					//select graphic rendition, only changes how text looks
+					cleaned += &function;
+					coloured = true;
+				}
+			},
+			Token::String(_) if in_control_string => (),
+			//anything the parser didnt recognise, like 8 bit C1 codes
+			Token::String(string) => cleaned.extend(string.chars().filter(|character| !character.is_control())),
+		}
+	}
+	//so the colours dont leak into whatever we print next
+	if coloured {
+		cleaned += "\x1b[0m";
+	}
+	cleaned
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn colours_are_kept_and_reset(){
+		assert_eq!(clean_text("\x1b[31mred"),"\x1b[31mred\x1b[0m");
+		assert_eq!(plain_text("\x1b[31mred"),"red");
+		assert_eq!(clean_text("plain"),"plain");
+	}
+
+	#[test]
+	fn screen_and_cursor_codes_go(){
+		assert_eq!(clean_text("a\x1b[2Jb\x1b[10;10Hc"),"abc");
+		//save and restore cursor, the parser leaves the final byte behind but without the escape its just text
+		assert!(!clean_text("a\x1b7b\x1b8c").contains('\x1b'));
+	}
+
+	#[test]
+	fn control_strings_go_entirely(){
+		//window title, ended with BEL and with ST
+		assert_eq!(clean_text("a\x1b]0;pwned\x07b"),"ab");
+		assert_eq!(clean_text("a\x1b]52;c;aGk=\x1b\\b"),"ab");
+	}
+
+	#[test]
+	fn line_breaks_become_spaces(){
+		assert_eq!(plain_text("one\ntwo\rthree\tfour"),"one two three four");
+	}
+
+	#[test]
+	fn stray_control_characters_go(){
+		assert_eq!(plain_text("a\u{9b}2Jb\u{7f}c\u{0}"),"a2Jbc");
+	}
+}
@@ -1,145 +1,571 @@
 #![feature(unix_socket_ancillary_data)]
 use std::io;
-use std::os::fd::AsRawFd;
-use chrono::{Local};
+use std::os::fd::{AsFd,AsRawFd};
+use chrono::{DateTime,Local};
 use std::path::Path;
 use std::fs;
+use std::fs::{DirBuilder,Permissions};
+use std::os::unix::fs::{DirBuilderExt,FileTypeExt,PermissionsExt};
 use std::time::{Duration,Instant};
+use std::collections::VecDeque;
 extern crate libnotify;
 use std::io::{Write,Read};
-use std::thread;
-use std::os::unix::net::{UnixListener, SocketAncillary};
-use nix::unistd::gethostname;
+use std::os::unix::net::{UnixListener, UnixStream, SocketAncillary};
+use nix::unistd::{gethostname,getuid};
+use nix::sys::socket::{getsockopt,sockopt::PeerCredentials};
+use nix::poll::{poll,PollFd,PollFlags,PollTimeout};
+use nix::errno::Errno;
 use std::net::{TcpListener, TcpStream, SocketAddr};
+use vanillaproto::{Frame,FrameBuffer,FrameType,Hello,Capabilities,Config,Message,Receipt,Session,Handshake,Keypair,write_frame,check_hello,check_name,unexpected_frame,KnownPeers,Trust,Caller,Verdict,UnknownCallers,load_identity,socket_path,Request,Response,Pending,Handover,Status,next_request,write_response};
 
 pub struct Connection {
+	//what ipc requests call them by
+	id: u64,
 	stream: TcpStream,
 	address: SocketAddr,
-	message_buffer: String,
-	name: String,
+	//raw bytes, only decoded as UTF-8 once a whole frame has arrived
+	message_buffer: FrameBuffer,
+	//frames waiting for their socket to take them, it never blocks
+	outgoing: Vec<u8>,
+	//how far through connecting they are
+	stage: Stage,
+	//when they connected, they get HANDSHAKE_TIMEOUT to finish the handshake
+	connected: Instant,
+	//what the caller told us about themselves
+	peer: Hello,
+	//features both sides support
+	capabilities: Capabilities,
+	//set once the handshake is done
+	session: Option<Session>,
+	//shown with every notification if their key isnt the one we know
+	warning: Option<String>,
+	//held without notifications, see the unknown_callers setting
+	quiet: bool,
+	//when we last notified about them, for the last minute
+	notifications: VecDeque<Instant>,
+	//went over notifications_per_minute
+	muted: bool,
+	//texts that arrived while on hold, for whoever picks up
+	backlog: VecDeque<(DateTime<Local>,Message)>,
+	//texts we sent them for the user while on hold
+	sent: u64,
+	last_heard: Instant,
+	last_ping: Instant,
+	//why the connection should be dropped
+	dead: Option<String>,
 }
 
-const SOCKET_LOCATION: &str = "/tmp/vanillachatd.socket";
+enum Stage {
+	//waiting for their hello
+	Hello,
+	//swapping handshake frames
+	Handshake(Box<Handshake>),
+	//encrypted, and waiting for someone to pick up
+	Held,
+	//being taken by vchat, left alone until it says it has them
+	Handover,
+}
+
+//a vchat asking us something, serviced by the poll loop like callers so nobody waits on it
+struct IpcConnection {
+	stream: UnixStream,
+	//bytes of the request, then of vchat saying it has the caller
+	incoming: Vec<u8>,
+	//our answer, waiting for the socket to take it
+	outgoing: Vec<u8>,
+	stage: IpcStage,
+	//they get IPC_TIMEOUT for the whole exchange
+	connected: Instant,
+	//why the connection should be dropped
+	dead: Option<String>,
+}
+
+enum IpcStage {
+	//waiting for the request
+	Request,
+	//taking the caller with this id, their socket goes once the answer has
+	Handover {id: u64, sent: bool},
+	//closed once the answer has gone
+	Answered,
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+//texts kept for each caller on hold, the oldest go first
+const BACKLOG_LENGTH: usize = 100;
+//bytes waiting to go to a caller before we decide they have stopped reading
+const MAX_OUTGOING: usize = 256 * 1024;
+//how long vchat gets to send its request, read the answer and take a caller
+const IPC_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> io::Result<()>{
 	let mut connections: Vec<Connection> = vec![];
+	let mut ipc_connections: Vec<IpcConnection> = vec![];
+	let started = Instant::now();
+	let mut next_caller_id: u64 = 1;
 	let our_name: String = gethostname()?.into_string().unwrap_or("Unknown name".into());
+	let mut our_hello = Hello::new(&our_name,env!("CARGO_PKG_VERSION"));
+	let config = Config::load()?;
+	//so callers dont send anything longer than we take
+	our_hello.max_message_size = config.max_message_size.try_into().unwrap_or(u32::MAX);
+	//the same key as vchat, so picking up doesnt look like a different person
+	let keypair = load_identity()?;
 	//===== setup the listener ======
 	let port: u16 = 9567;
 	let addr = SocketAddr::from(([0,0,0,0],port));
 	let listener = TcpListener::bind(addr)?;
-	if Path::new(SOCKET_LOCATION).exists(){
-		//delete socket if it exists
-		fs::remove_file(SOCKET_LOCATION)?;
-	}
-	let ipc = UnixListener::bind(SOCKET_LOCATION)?;
+	let ipc = bind_ipc(&socket_path()?)?;
 	ipc.set_nonblocking(true)?;
 	//nonblocking
 	listener.set_nonblocking(true).expect("could not set listener to nonblocking");
 	loop{
+		//====== sleep until something happens ======
+		let mut fds = vec![PollFd::new(listener.as_fd(),PollFlags::POLLIN),PollFd::new(ipc.as_fd(),PollFlags::POLLIN)];
+		fds.extend(connections.iter().map(|connection| match (&connection.stage,connection.outgoing.is_empty()) {
+			//vchat is taking their socket, we dont touch it
+			(Stage::Handover,_) => PollFd::new(connection.stream.as_fd(),PollFlags::empty()),
+			(_,true) => PollFd::new(connection.stream.as_fd(),PollFlags::POLLIN),
+			(_,false) => PollFd::new(connection.stream.as_fd(),PollFlags::POLLIN | PollFlags::POLLOUT),
+		}));
+		fds.extend(ipc_connections.iter().map(|ipc_connection| PollFd::new(ipc_connection.stream.as_fd(),ipc_interest(ipc_connection))));
+		let timeout = next_deadline(&connections,&ipc_connections,&config)
+			.map(|wait| PollTimeout::try_from(wait.as_millis() + 1).unwrap_or(PollTimeout::MAX))
+			.unwrap_or(PollTimeout::NONE);
+		match poll(&mut fds,timeout) {
+			Ok(_) => (),
+			Err(Errno::EINTR) => continue,
+			Err(e) => return Err(e.into()),
+		}
+		let ready: Vec<bool> = fds.iter().map(|fd| fd.revents().is_some_and(|events| !events.is_empty())).collect();
+		drop(fds);
+		let (callers_ready,ipc_ready) = ready[2..].split_at(connections.len());
+		//====== receive any frames ======
+		for (connection,_) in connections.iter_mut().zip(callers_ready).filter(|(connection,ready)| **ready && !matches!(connection.stage,Stage::Handover)){
+			//keep going until the buffer is empty, poll only knows about the socket
+			while connection.dead.is_none() {
+				let frame = match recv_frame(connection){
+					Ok(Some(frame)) => frame,
+					Ok(None) => break,
+					Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+						connection.dead = Some("they hung up".into());
+						break;
+					},
+					Err(e) => {
+						//tell them what they did wrong, we cant find the next frame after it
+						if e.kind() == io::ErrorKind::InvalidData {
+							let _ = send_error(connection,&e.to_string());
+						}
+						connection.dead = Some(e.to_string());
+						break;
+					},
+				};
+				let result = match connection.stage {
+					Stage::Held => handle_message(connection,&frame,&config),
+					_ => continue_handshake(connection,&frame,&our_hello,&keypair,&config),
+				};
+				if let Err(e) = result {
+					connection.dead = Some(e.to_string());
+				}
+			}
+		}
+		//====== check on quiet callers ======
+		for connection in connections.iter_mut(){
+			let held = matches!(connection.stage,Stage::Held);
+			let connecting = matches!(connection.stage,Stage::Hello | Stage::Handshake(_));
+			if connecting && connection.connected.elapsed() >= HANDSHAKE_TIMEOUT {
+				let _ = send_error(connection,"Timed out waiting for the handshake.");
+				connection.dead = Some("timed out waiting for the handshake".into());
+			}else if !held || !connection.capabilities.contains(Capabilities::HEARTBEAT) {
+				continue;
+			}else if connection.last_heard.elapsed() >= config.heartbeat_timeout {
+				connection.dead = Some(format!("nothing heard for {}s",connection.last_heard.elapsed().as_secs()));
+				//in case they can still hear us
+				let _ = send_message(connection,&Message::Goodbye {reason: "timeout".into()});
+			}else if connection.last_ping.elapsed() >= config.heartbeat_interval {
+				connection.last_ping = Instant::now();
+				if let Err(e) = send_message(connection,&Message::Ping){
+					connection.dead = Some(e.to_string());
+				}
+			}
+		}
+		//====== send whatever their sockets will take ======
+		for connection in connections.iter_mut().filter(|connection| !connection.outgoing.is_empty() && !matches!(connection.stage,Stage::Handover)){
+			if let Err(e) = flush_outgoing(connection) && connection.dead.is_none() {
+				connection.dead = Some(e.to_string());
+			}
+		}
+		//====== drop dead connections ======
+		connections.retain(|connection| match (&connection.dead,&connection.stage) {
+			(Some(reason),Stage::Held) => {
+				println!("connection {} dead: {}",connection.address,printable(reason));
+				false
+			},
+			(Some(reason),_) => {
+				eprintln!("Dropped connection {}: {}",connection.address,printable(reason));
+				false
+			},
+			(None,_) => true,
+		});
 		//====== accept tcp connections ======
-		let _ = match listener.accept(){
-			Ok(connection) => handle_connection(&mut connections,connection.0,connection.1,&our_name),
-			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
-			Err(e) => panic!("Error: {e}"),
-		};
-		//====== accept ipc connections ======
-		let _ = handle_ipc(&ipc,&mut connections);
-		//====== receive any messages ======
-		for i in 0..connections.len(){
-			let message = recv_msg(connections.get_mut(i).unwrap(),None);
-			if message.is_some(){
-				let _ = send_notification(&connections[i],message.unwrap());
+		if ready[0] {
+			let _ = match listener.accept(){
+				Ok(connection) => {
+					next_caller_id += 1;
+					handle_connection(&mut connections,next_caller_id - 1,connection.0,connection.1,&our_hello,&config)
+						.map_err(|e| eprintln!("Dropped connection {}: {e}",connection.1))
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+				Err(e) => panic!("Error: {e}"),
+			};
+		}
+		//====== answer vchat ======
+		let mut shutdown = false;
+		for (ipc_connection,_) in ipc_connections.iter_mut().zip(ipc_ready).filter(|(_,ready)| **ready){
+			match serve_ipc(ipc_connection,&mut connections,started,&config) {
+				Ok(stop) => shutdown |= stop,
+				Err(e) => ipc_connection.dead = Some(e.to_string()),
 			}
 		}
-		//====== verify sockets are still alive ======
-		let mut connections_to_delete = vec![];
-		for connection in connections.iter().enumerate(){
-			if !is_alive(connection.1){
-				println!("connection {} dead",connection.1.address);
-				connections_to_delete.push(connection.0);
+		//====== drop finished ipc connections ======
+		ipc_connections.retain(|ipc_connection| {
+			let reason = match (&ipc_connection.dead,&ipc_connection.stage) {
+				(Some(reason),_) => reason.clone(),
+				(None,IpcStage::Answered) if ipc_connection.outgoing.is_empty() => return false,
+				(None,_) if ipc_connection.connected.elapsed() >= IPC_TIMEOUT => "timed out".into(),
+				(None,_) => return true,
+			};
+			eprintln!("ipc request failed: {reason}");
+			if let IpcStage::Handover {id,..} = ipc_connection.stage {
+				give_back(&mut connections,id);
+			}
+			false
+		});
+		//====== accept ipc connections ======
+		if ready[1] {
+			match accept_ipc(&ipc) {
+				Ok(Some(ipc_connection)) => ipc_connections.push(ipc_connection),
+				Ok(None) => (),
+				Err(e) => eprintln!("ipc request failed: {e}"),
 			}
 		}
-		for connection_to_delete in connections_to_delete{
-			connections.swap_remove(connection_to_delete);
+		if shutdown {
+			break;
 		}
-
-		//====== yield cpu time to other processes ======
-		thread::sleep(Duration::from_millis(20));
 	}
+	//====== hang up on everyone ======
+	for mut connection in connections{
+		let _ = match connection.stage {
+			Stage::Held => send_message(&mut connection,&Message::Goodbye {reason: "the daemon is shutting down".into()}),
+			//vchat may have them already
+			Stage::Handover => continue,
+			_ => send_error(&mut connection,"The daemon is shutting down."),
+		};
+		//only what goes straight away, nobody gets to keep us running
+		let _ = flush_outgoing(&mut connection);
+	}
+	//====== last answers, including to whoever asked us to stop ======
+	for mut ipc_connection in ipc_connections{
+		let _ = write_queued(&mut ipc_connection.stream,&mut ipc_connection.outgoing);
+	}
+	let _ = fs::remove_file(socket_path()?);
+	println!("shut down");
+	Ok(())
 }
-fn handle_connection(connections: &mut Vec<Connection>, stream: TcpStream, address: SocketAddr, our_name: &String) -> Result<(), io::Error>{
+//greets a new caller, the rest of connecting is done by continue_handshake as their frames arrive
+fn handle_connection(connections: &mut Vec<Connection>, id: u64, stream: TcpStream, address: SocketAddr, our_hello: &Hello, config: &Config) -> Result<(), io::Error>{
 	println!("New connection: {}",address);
+	//====== dont let anyone fill the waiting room ======
+	if connections.len() >= config.max_pending_callers {
+		return Err(io::Error::other(format!("Too many callers waiting already, the most allowed is {}.",config.max_pending_callers)));
+	}
+	let from_address = connections.iter().filter(|connection| connection.address.ip() == address.ip()).count();
+	if from_address >= config.max_connections_per_address {
+		return Err(io::Error::other(format!("Too many callers from {} already, the most allowed is {}.",address.ip(),config.max_connections_per_address)));
+	}
+	//====== drop blocked callers as soon as we know enough ======
+	screen(config,Caller {address: address.ip(), name: None, key: None})?;
+	//a caller that stops reading cant hold up everyone else
+	stream.set_nonblocking(true)?;
+	let mut message_buffer = FrameBuffer::new();
+	message_buffer.set_max_payload(config.max_handshake_payload());
 	let mut connection = Connection {
-		stream: stream,
-		address: address,
-		message_buffer: "".to_string(),
-		name: String::new()
+		id,
+		stream,
+		address,
+		message_buffer,
+		outgoing: vec![],
+		stage: Stage::Hello,
+		connected: Instant::now(),
+		peer: Hello::new("",""),
+		capabilities: Capabilities::NONE,
+		session: None,
+		warning: None,
+		quiet: false,
+		notifications: VecDeque::new(),
+		muted: false,
+		backlog: VecDeque::new(),
+		sent: 0,
+		last_heard: Instant::now(),
+		last_ping: Instant::now(),
+		dead: None,
 	};
-	//====== send our name ======
-	send_msg(&mut connection.stream,our_name.clone())?;
-	//======= give the client 5s to send their name ======
-	let timeout = Duration::from_secs(5);
-	let name = recv_msg(&mut connection,Some(timeout)).unwrap_or("name unknown".into());
-	connection.name = name;
+	//====== send our hello ======
+	write_frame(&mut connection.outgoing,FrameType::Hello,&our_hello.encode())?;
 	connections.push(connection);
 	Ok(())
 }
-fn is_alive(connection: &Connection) -> bool{
-	connection.stream.set_nonblocking(true).expect("could not place connection socket into nonblocking mode");
-	let mut buf = [0; 1];
-	let result = connection.stream.peek(&mut buf);
-	connection.stream.set_nonblocking(false).expect("could not place connection socket into blocking mode");
-	match result {
-		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
-		Ok(size) => match size {
-			0 => false,
-			_ => true,
+//takes the next frame from a caller who is still connecting
+fn continue_handshake(connection: &mut Connection, frame: &Frame, our_hello: &Hello, keypair: &Keypair, config: &Config) -> io::Result<()>{
+	let address = connection.address.ip();
+	match &mut connection.stage {
+		//====== their hello ======
+		Stage::Hello => {
+			if frame.kind != FrameType::Hello {
+				return Err(unexpected_frame(frame,"a hello"));
+			}
+			connection.peer = Hello::decode(&frame.payload)?;
+			screen(config,Caller {address, name: Some(&connection.peer.name), key: None})?;
+			check_hello(&mut connection.outgoing,our_hello,&connection.peer,config.max_name_length)?;
+			connection.capabilities = our_hello.negotiate(&connection.peer);
+			//====== encrypt everything from here on ======
+			//they connected to us, so they start it
+			let handshake = Handshake::new(false,keypair,&connection.peer,our_hello,None)?;
+			connection.stage = Stage::Handshake(Box::new(handshake));
+			Ok(())
 		},
-		Err(_) => false,
+		//====== their half of the handshake ======
+		Stage::Handshake(handshake) => {
+			if let Err(e) = handshake.receive_frame(frame) {
+				//they may think they are done already, so tell them why
+				let _ = write_frame(&mut connection.outgoing,FrameType::Error,e.to_string().as_bytes());
+				return Err(e);
+			}
+			while let Some(frame) = handshake.next_frame()? {
+				write_frame(&mut connection.outgoing,frame.kind,&frame.payload)?;
+			}
+			if !handshake.is_finished() {
+				return Ok(());
+			}
+			let Stage::Handshake(handshake) = std::mem::replace(&mut connection.stage,Stage::Held) else {unreachable!()};
+			let session = handshake.finish()?;
+			hold(connection,session,config)
+		},
+		Stage::Held | Stage::Handover => Err(io::Error::other("Already connected.")),
+	}
+}
+//last checks once we know their key, then they wait to be picked up
+fn hold(connection: &mut Connection, session: Session, config: &Config) -> io::Result<()>{
+	let caller = Caller {address: connection.address.ip(), name: Some(&connection.peer.name), key: Some(&session.remote_static)};
+	if screen(config,caller)? == Verdict::Unknown {
+		match config.unknown_callers {
+			UnknownCallers::Accept => (),
+			UnknownCallers::Quiet => connection.quiet = true,
+			UnknownCallers::Deny => return Err(io::Error::new(io::ErrorKind::PermissionDenied,"Unknown caller, no allow rule matches them.")),
+		}
 	}
+	//only now do they get to send anything big
+	connection.message_buffer.set_max_payload(config.max_frame_payload());
+	//====== make sure they are who they were last time ======
+	let mut known_peers = KnownPeers::load()?;
+	for warning in known_peers.warnings(){
+		eprintln!("{warning}");
+	}
+	match known_peers.check(&connection.peer.name,&session.remote_static) {
+		Trust::New => known_peers.pin(&connection.peer.name,&session.remote_static)?,
+		Trust::Known | Trust::Verified => (),
+		trust => {
+			connection.warning = trust.warning(&connection.peer.name,&session.remote_static);
+			let _ = send_notification(connection,String::new());
+		},
+	}
+	connection.session = Some(session);
+	connection.last_heard = Instant::now();
+	connection.last_ping = Instant::now();
+	println!("{} is <{}> running vanillachat {}",connection.address,connection.peer.name,printable(&connection.peer.software_version));
+	Ok(())
 }
-fn recv_msg(connection: &mut Connection,timeout: Option<Duration>) -> Option<String>{
-	//switch to nonblocking
-	connection.stream.set_nonblocking(true).expect("could not place connection socket into nonblocking mode");
-	//start the timer
-	let stopwatch = Instant::now();
+//deny rules drop the caller without telling them why
+fn screen(config: &Config, caller: Caller) -> io::Result<Verdict>{
+	match config.screen(&caller) {
+		Verdict::Deny => Err(io::Error::new(io::ErrorKind::PermissionDenied,"Blocked by a deny rule.")),
+		verdict => Ok(verdict),
+	}
+}
+//acts on a message from a caller on hold
+fn handle_message(connection: &mut Connection, frame: &Frame, config: &Config) -> io::Result<()>{
+	let message = match open_message(connection,frame) {
+		Ok(message) => message,
+		Err(e) => {
+			if e.kind() == io::ErrorKind::InvalidData {
+				let _ = send_error(connection,&e.to_string());
+			}
+			return Err(e);
+		},
+	};
+	connection.last_heard = Instant::now();
+	match message{
+		Message::Text {id,body,reply_to} => {
+			//it has reached our machine, even if nobody has picked up yet
+			if connection.capabilities.contains(Capabilities::RECEIPTS){
+				let _ = send_message(connection,&Message::Ack {id, receipt: Receipt::Delivered});
+			}
+			if connection.backlog.len() == BACKLOG_LENGTH {
+				connection.backlog.pop_front();
+			}
+			connection.backlog.push_back((Local::now(),Message::Text {id, body: body.clone(), reply_to}));
+			if notification_allowed(connection,config) {
+				let _ = send_notification(connection,body);
+			}
+		},
+		//====== keep the backlog as they meant it ======
+		Message::Edit {id,body} => {
+			for (_,message) in connection.backlog.iter_mut(){
+				if let Message::Text {id: text_id,body: text_body,..} = message && *text_id == id {
+					*text_body = body.clone();
+				}
+			}
+		},
+		Message::Delete {id} => connection.backlog.retain(|(_,message)| !matches!(message,Message::Text {id: text_id,..} if *text_id == id)),
+		Message::Nick {name} => {
+			if let Some(reason) = check_name(&name,config.max_name_length) {
+				let _ = send_error(connection,&reason);
+				return Err(io::Error::new(io::ErrorKind::InvalidData,reason));
+			}
+			println!("{} is now known as <{name}>",connection.address);
+			//the new name may be pinned to someone else's key
+			let Some(session) = &connection.session else {return Err(io::Error::from(io::ErrorKind::NotConnected))};
+			let warning = KnownPeers::load()?.check(&name,&session.remote_static).warning(&name,&session.remote_static);
+			connection.peer.name = name;
+			if warning.is_some() {
+				connection.warning = warning;
+				if notification_allowed(connection,config) {
+					let _ = send_notification(connection,String::new());
+				}
+			}
+		},
+		Message::Ping => {let _ = send_message(connection,&Message::Pong);},
+		Message::Goodbye {reason} => connection.dead = Some(format!("they left ({reason})")),
+		_ => (),
+	}
+	Ok(())
+}
+//the next whole frame, if one has arrived
+fn recv_frame(connection: &mut Connection) -> io::Result<Option<Frame>>{
 	//====== read ======
-	let mut buffer = [0; 1];
-	let return_value = loop{
-		//exit if timeout reached
-		if timeout.is_some(){
-			if stopwatch.elapsed() >= timeout.unwrap(){
-				break None;
-			}
-		}
-		let count = match connection.stream.read(&mut buffer){
-			Ok(count) => count,
-			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
-			Err(e) => {eprintln!("error whilst reading: {e}");break None},
+	let mut buffer = [0; 1024];
+	loop{
+		//====== return the next complete frame ======
+		match connection.message_buffer.next_frame(){
+			Ok(Some(frame)) => match frame.kind {
+				//newer peers may send things we dont understand yet
+				FrameType::Unknown(_) => continue,
+				_ => break Ok(Some(frame)),
+			},
+			Ok(None) => (),
+			Err(e) => break Err(e),
+		}
+		match connection.stream.read(&mut buffer){
+			Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+			Ok(count) => connection.message_buffer.extend(&buffer[..count]),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(None),
+			Err(e) => break Err(e),
 		};
-		if count == 1 {
-			if buffer[0] == 0x04{
-				//end of transmition
-				let ret = connection.message_buffer.clone();
-				connection.message_buffer.clear();
-				break Some(ret);
-			}
-			//====== append the char to the buffer ======
-			let ch = char::from_u32(buffer[0] as u32).unwrap();
-			connection.message_buffer.push(ch);
-		}else{
-			break None;
+	}
+}
+//writes as much of the outgoing queue as the socket will take without blocking
+fn flush_outgoing(connection: &mut Connection) -> io::Result<()>{
+	write_queued(&mut connection.stream,&mut connection.outgoing)
+}
+fn write_queued<T: Write>(stream: &mut T, outgoing: &mut Vec<u8>) -> io::Result<()>{
+	let mut written = 0;
+	let result = loop {
+		if written == outgoing.len() {
+			break Ok(());
+		}
+		match stream.write(&outgoing[written..]){
+			Ok(0) => break Err(io::Error::from(io::ErrorKind::WriteZero)),
+			Ok(count) => written += count,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+			Err(e) => break Err(e),
 		}
 	};
-	//unswitch from nonblocking
-	connection.stream.set_nonblocking(false).expect("could not place connection socket into blocking mode");
-	return_value
+	outgoing.drain(..written);
+	result
+}
+//decrypts a frame from a caller on hold
+fn open_message(connection: &mut Connection, frame: &Frame) -> io::Result<Message>{
+	let Some(session) = &mut connection.session else {return Err(io::Error::from(io::ErrorKind::NotConnected))};
+	Message::decode(&session.receiving.open(frame)?)
+}
+fn send_message(connection: &mut Connection, message: &Message) -> io::Result<()>{
+	send_frame(connection,&message.encode())
+}
+//tells the caller why they are being dropped, encrypted if we can
+fn send_error(connection: &mut Connection, reason: &str) -> io::Result<()>{
+	match connection.session {
+		Some(_) => send_frame(connection,&Frame::new(FrameType::Error,reason.as_bytes().to_vec())),
+		None => send_msg(&mut connection.outgoing,FrameType::Error,reason.into()),
+	}
+}
+fn send_frame(connection: &mut Connection, frame: &Frame) -> io::Result<()>{
+	let Some(session) = &mut connection.session else {return Err(io::Error::from(io::ErrorKind::NotConnected))};
+	let frame = session.sending.seal(frame)?;
+	write_frame(&mut connection.outgoing,frame.kind,&frame.payload)?;
+	if connection.outgoing.len() > MAX_OUTGOING {
+		let reason = format!("stopped reading, {} bytes waiting to be sent",connection.outgoing.len());
+		connection.dead = Some(reason.clone());
+		return Err(io::Error::other(reason));
+	}
+	Ok(())
+}
+//how long until a caller needs pinging, has gone quiet for too long or ran out of time to connect, or vchat runs out of time
+fn next_deadline(connections: &[Connection], ipc_connections: &[IpcConnection], config: &Config) -> Option<Duration>{
+	connections.iter()
+		.filter_map(|connection| match connection.stage {
+			Stage::Held if connection.capabilities.contains(Capabilities::HEARTBEAT) => {
+				let ping = config.heartbeat_interval.saturating_sub(connection.last_ping.elapsed());
+				let timeout = config.heartbeat_timeout.saturating_sub(connection.last_heard.elapsed());
+				Some(ping.min(timeout))
+			},
+			Stage::Held | Stage::Handover => None,
+			_ => Some(HANDSHAKE_TIMEOUT.saturating_sub(connection.connected.elapsed())),
+		})
+		.chain(ipc_connections.iter().map(|ipc_connection| IPC_TIMEOUT.saturating_sub(ipc_connection.connected.elapsed())))
+		.min()
+}
+//counts notifications against notifications_per_minute, muting or unmuting the caller
+fn notification_allowed(connection: &mut Connection, config: &Config) -> bool{
+	let minute = Duration::from_secs(60);
+	while connection.notifications.front().is_some_and(|sent| sent.elapsed() >= minute) {
+		connection.notifications.pop_front();
+	}
+	if connection.notifications.len() >= config.notifications_per_minute {
+		if !connection.muted {
+			println!("connection {} muted: more than {} notifications a minute",connection.address,config.notifications_per_minute);
+			connection.muted = true;
+		}
+		return false;
+	}
+	if connection.muted {
+		println!("connection {} unmuted",connection.address);
+		connection.muted = false;
+	}
+	connection.notifications.push_back(Instant::now());
+	true
 }
 fn send_notification(connection: &Connection, message: String) -> Result<(),String>{
-	println!("new message: {message}");
+	//make sure they see it every time, not just once
+	let message = match &connection.warning {
+		Some(warning) => format!("{warning}\n\n{message}"),
+		None => message,
+	};
+	//line breaks are ours or theirs, everything else they could have sent goes
+	println!("new message: {}",message.lines().map(printable).collect::<Vec<String>>().join("\n"));
+	if connection.quiet {
+		return Ok(());
+	}
 	libnotify::init("vanillachatd")?;
-	let notification = libnotify::Notification::new(format!("vanillachat @{}",connection.name).as_str(),Some(message.as_str()),None);
+	let notification = libnotify::Notification::new(format!("vanillachat @{}",connection.peer.name).as_str(),Some(message.as_str()),None);
 	match notification.show(){
 		Ok(_) => Ok(()),
 		Err(e) => Err(e.to_string()),
@@ -147,41 +573,263 @@ fn send_notification(connection: &Connection, message: String) -> Result<(),Stri
 	libnotify::uninit();
 	Ok(())
 }
-fn handle_ipc(listener: &UnixListener, connections: &mut Vec<Connection>) -> io::Result<()>{
+//listens somewhere only we can get to, for vchat to pick up calls
+fn bind_ipc(path: &Path) -> io::Result<UnixListener>{
+	if let Some(dir) = path.parent() {
+		DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+	}
+	//left behind by an earlier run, but never delete anything that isnt a socket
+	match fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+		Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists,format!("{} is in the way of the daemon's socket.",path.display()))),
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+		Err(e) => return Err(e),
+	}
+	let listener = UnixListener::bind(path)?;
+	fs::set_permissions(path,Permissions::from_mode(0o600))?;
+	Ok(listener)
+}
+//a new vchat, its request is read by serve_ipc once it arrives
+fn accept_ipc(listener: &UnixListener) -> io::Result<Option<IpcConnection>>{
 	//====== accept connection ======
-	let (mut connection, _addr) = match listener.accept(){
+	let (stream, _addr) = match listener.accept(){
 		Ok(c) => c,
 		//yeild if no connection ready
-		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
 		Err(e) => return Err(e),
 	};
-	println!("new ipc connection");
-	//====== send over client info ======
-	//send the number of connections
-	connection.write_all(&u32::to_be_bytes(connections.len().try_into().unwrap_or(u32::MAX)))?;
-	for i in 0..connections.len(){
-		//send timestamp
-		let timestamp: u64 = Local::now().timestamp().try_into().unwrap_or(0);
-		connection.write_all(&mut u64::to_be_bytes(timestamp))?;
-		//send name
-		send_msg(&mut connection,connections[i].name.clone())?;
-	}
-	//====== let client select socket ======
-	let mut selected_buffer = [0; 4];
-	connection.read_exact(&mut selected_buffer)?;
-	let selected_connection = u32::from_be_bytes(selected_buffer);
-	//====== send the socket ======
-	println!("ipc connection took [{:?}]",connections[selected_connection as usize].address);
-	let socket_stream_binder = connections.swap_remove(selected_connection as usize).stream;
-	let socket_fd = socket_stream_binder.as_raw_fd();
+	//====== only our own user gets to pick up ======
+	let uid = getsockopt(&stream,PeerCredentials).map_err(io::Error::from)?.uid();
+	if uid != getuid().as_raw() {
+		println!("refused ipc connection from uid {uid}");
+		return Err(io::Error::new(io::ErrorKind::PermissionDenied,format!("uid {uid} tried to pick up our calls.")));
+	}
+	//a vchat that stops reading cant hold up everyone else
+	stream.set_nonblocking(true)?;
+	Ok(Some(IpcConnection {
+		stream,
+		incoming: vec![],
+		outgoing: vec![],
+		stage: IpcStage::Request,
+		connected: Instant::now(),
+		dead: None,
+	}))
+}
+//takes a vchat as far as it can go without waiting, true if it asked us to shut down
+fn serve_ipc(ipc_connection: &mut IpcConnection, connections: &mut Vec<Connection>, started: Instant, config: &Config) -> io::Result<bool>{
+	let mut shutdown = false;
+	let hung_up = match ipc_connection.stage {
+		IpcStage::Request | IpcStage::Handover {sent: true,..} => read_ipc(ipc_connection)?,
+		_ => false,
+	};
+	//====== answer the request ======
+	if let IpcStage::Request = ipc_connection.stage {
+		let answer = match next_request(&mut ipc_connection.incoming) {
+			Ok(Some(request)) => {
+				shutdown = request == Request::Shutdown;
+				answer_ipc(request,connections,started,config)
+			},
+			Ok(None) if hung_up => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,"vchat hung up without asking anything.")),
+			Ok(None) => return Ok(false),
+			Err(e) => Err(e),
+		};
+		match answer {
+			Ok(Answer::Response(response)) => {
+				write_response(&mut ipc_connection.outgoing,&response)?;
+				ipc_connection.stage = IpcStage::Answered;
+			},
+			Ok(Answer::Take(index)) => {
+				let selected = &mut connections[index];
+				write_response(&mut ipc_connection.outgoing,&Response::Taken(handover(selected)?))?;
+				//they stay on hold until their socket has really gone to vchat
+				selected.stage = Stage::Handover;
+				ipc_connection.stage = IpcStage::Handover {id: selected.id, sent: false};
+			},
+			Err(e) => {
+				eprintln!("ipc request failed: {e}");
+				write_response(&mut ipc_connection.outgoing,&Response::Error {reason: e.to_string()})?;
+				ipc_connection.stage = IpcStage::Answered;
+			},
+		}
+	}
+	//====== let the caller go once vchat says it has them ======
+	if let IpcStage::Handover {id,sent: true} = ipc_connection.stage {
+		if ipc_connection.incoming.len() >= 2 {
+			if ipc_connection.incoming[..2] != *b"Ok" {
+				return Err(io::Error::new(io::ErrorKind::InvalidData,"vchat didnt say it had the socket."));
+			}
+			let index = taking(connections,id)?;
+			let selected = connections.swap_remove(index);
+			println!("ipc connection took [{:?}]",selected.address);
+			//closes our end before vchat is told it can use theirs
+			drop(selected);
+			ipc_connection.outgoing.extend_from_slice(b"Ok");
+			ipc_connection.stage = IpcStage::Answered;
+		}else if hung_up {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof,"vchat hung up before saying it had the socket."));
+		}
+	}
+	//====== send the answer, then the socket ======
+	write_queued(&mut ipc_connection.stream,&mut ipc_connection.outgoing)?;
+	if let IpcStage::Handover {id,sent: false} = ipc_connection.stage && ipc_connection.outgoing.is_empty() {
+		let index = taking(connections,id)?;
+		match send_socket(&ipc_connection.stream,&connections[index]) {
+			Ok(count) => {
+				//the socket went with the first byte, the rest is like any other
+				ipc_connection.outgoing.extend_from_slice(&b"Ok"[count..]);
+				ipc_connection.stage = IpcStage::Handover {id,sent: true};
+			},
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(shutdown)
+}
+//reads whatever vchat has sent, true once it has hung up
+fn read_ipc(ipc_connection: &mut IpcConnection) -> io::Result<bool>{
+	let mut buffer = [0; 1024];
+	loop{
+		match ipc_connection.stream.read(&mut buffer){
+			Ok(0) => break Ok(true),
+			Ok(count) => ipc_connection.incoming.extend_from_slice(&buffer[..count]),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+			Err(e) => break Err(e),
+		}
+	}
+}
+fn ipc_interest(ipc_connection: &IpcConnection) -> PollFlags{
+	let interest = match ipc_connection.stage {
+		IpcStage::Request | IpcStage::Handover {sent: true,..} => PollFlags::POLLIN,
+		//waiting to send the socket
+		IpcStage::Handover {sent: false,..} => PollFlags::POLLOUT,
+		IpcStage::Answered => PollFlags::empty(),
+	};
+	match ipc_connection.outgoing.is_empty() {
+		true => interest,
+		false => interest | PollFlags::POLLOUT,
+	}
+}
+//the caller a vchat is part way through taking
+fn taking(connections: &[Connection], id: u64) -> io::Result<usize>{
+	connections.iter()
+		.position(|connection| connection.id == id && matches!(connection.stage,Stage::Handover))
+		.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,format!("Caller {id} went away while being taken.")))
+}
+//a caller vchat never said it had goes back on hold
+fn give_back(connections: &mut [Connection], id: u64){
+	if let Ok(index) = taking(connections,id) {
+		let connection = &mut connections[index];
+		connection.stage = Stage::Held;
+		//still ours, so back to how the poll loop needs it
+		if let Err(e) = connection.stream.set_nonblocking(true) {
+			connection.dead = Some(e.to_string());
+		}
+	}
+}
+//what an ipc request gets back, a caller being taken is more than bytes
+enum Answer {
+	Response(Response),
+	Take(usize),
+}
+fn answer_ipc(request: Request, connections: &mut Vec<Connection>, started: Instant, config: &Config) -> io::Result<Answer>{
+	//callers still connecting cant be picked up yet
+	let held = |connection: &&Connection| matches!(connection.stage,Stage::Held);
+	let find = |connections: &[Connection], id: u64| connections.iter()
+		.position(|connection| connection.id == id && matches!(connection.stage,Stage::Held))
+		.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,format!("Nobody with id {id} is on hold.")));
+	Ok(Answer::Response(match request {
+		Request::ListPending => {
+			println!("ipc connection listed callers");
+			Response::Pending(connections.iter().filter(held).map(|connection| Pending {
+				id: connection.id,
+				//when they called, where from, how much they have said and the hello they gave us
+				called: (Local::now().timestamp() - connection.connected.elapsed().as_secs() as i64).try_into().unwrap_or(0),
+				address: connection.address.to_string(),
+				queued: connection.backlog.len().try_into().unwrap_or(u32::MAX),
+				peer: connection.peer.clone(),
+			}).collect())
+		},
+		Request::Take {id} => {
+			return Ok(Answer::Take(find(connections,id)?));
+		},
+		Request::Reject {id,reason} => {
+			let mut selected = connections.swap_remove(find(connections,id)?);
+			println!("ipc connection rejected [{:?}]: {reason}",selected.address);
+			let _ = send_message(&mut selected,&Message::Goodbye {reason});
+			let _ = flush_outgoing(&mut selected);
+			Response::Done
+		},
+		Request::SendToPending {id,body} => {
+			let index = find(connections,id)?;
+			let selected = &mut connections[index];
+			//they told us what they take in their hello
+			let limit = config.max_message_size.min(selected.peer.max_message_size as usize);
+			if body.len() > limit {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput,format!("A {} byte message is over the {limit} byte limit.",body.len())));
+			}
+			//ids count up from 1, whoever picks up carries on from here
+			selected.sent += 1;
+			let text = Message::Text {id: selected.sent, body, reply_to: None};
+			println!("ipc connection sent a message to [{:?}]",selected.address);
+			if let Err(e) = send_message(selected,&text) {
+				selected.dead = Some(e.to_string());
+				return Err(e);
+			}
+			Response::Done
+		},
+		Request::Status => {
+			let pending = connections.iter().filter(held).count();
+			let connecting = connections.iter().filter(|connection| matches!(connection.stage,Stage::Hello | Stage::Handshake(_))).count();
+			Response::Status(Status {
+				software_version: env!("CARGO_PKG_VERSION").into(),
+				pending: pending.try_into().unwrap_or(u32::MAX),
+				connecting: connecting.try_into().unwrap_or(u32::MAX),
+				uptime: started.elapsed().as_secs(),
+			})
+		},
+		Request::Shutdown => {
+			println!("ipc connection asked us to shut down");
+			Response::Done
+		},
+	}))
+}
+//the encryption, anything half read and the backlog, for whoever picks up
+fn handover(selected: &Connection) -> io::Result<Handover>{
+	let Some(session) = selected.session.clone() else {return Err(io::Error::from(io::ErrorKind::NotConnected))};
+	Ok(Handover {
+		session,
+		pending: selected.message_buffer.pending().to_vec(),
+		outgoing: selected.outgoing.clone(),
+		sent: selected.sent,
+		backlog: selected.backlog.iter()
+			.map(|(time,message)| (time.timestamp().try_into().unwrap_or(0),message.clone()))
+			.collect(),
+	})
+}
+//sends the caller's socket attached to an Ok, vchat says Ok back once it has it
+fn send_socket(stream: &UnixStream, selected: &Connection) -> io::Result<usize>{
+	//vchat expects a normal socket, and the flag goes with it
+	selected.stream.set_nonblocking(false)?;
+	let socket_fd = selected.stream.as_raw_fd();
 	let mut ancillary_buffer = [0; 128];
 	let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
 	ancillary.add_fds(&[socket_fd]);
 	let data = io::IoSlice::new("Ok".as_ref());
-	connection.send_vectored_with_ancillary(&[data],&mut ancillary)?;
-	Ok(())
+	let result = stream.send_vectored_with_ancillary(&[data],&mut ancillary);
+	if result.is_err() {
+		//not sent, so back to how the poll loop needs it
+		selected.stream.set_nonblocking(true)?;
+	}
+	result
+}
+//peer text for our own output, with control characters written out so they cant reach the terminal
+fn printable(text: &str) -> String{
+	text.chars().map(|character| match character.is_control() {
+		true => character.escape_default().to_string(),
+		false => character.to_string(),
+	}).collect()
 }
-fn send_msg<T: Write>(connection: &mut T, message: String) -> io::Result<()>{
-	let bytes = (message + "\x04").into_bytes();
-	connection.write_all(&bytes)
+fn send_msg<T: Write>(connection: &mut T, kind: FrameType, message: String) -> io::Result<()>{
+	write_frame(connection,kind,message.as_bytes())
 }
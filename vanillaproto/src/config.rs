@@ -0,0 +1,143 @@
+//! Settings shared by vchat and vchatd, read from `~/.config/vanillachat/config`.
+//!
+//! The file is lines of `key = value`, blank lines and lines starting with `#`
+//! are ignored. Anything missing keeps its default. `allow` and `deny` can be
+//! given more than once, each line adds another rule.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::time::Duration;
+use crate::filter::{Rule,UnknownCallers,Caller,Verdict,screen};
+use crate::frame::DEFAULT_MAX_PAYLOAD;
+
+#[derive(Debug,Clone)]
+pub struct Config {
+	/// How often to ping a quiet peer.
+	pub heartbeat_interval: Duration,
+	/// How long a peer can stay silent before it is treated as gone.
+	pub heartbeat_timeout: Duration,
+	/// Callers vchatd lets through, see [`crate::filter`].
+	pub allow: Vec<Rule>,
+	/// Callers vchatd drops without telling anyone.
+	pub deny: Vec<Rule>,
+	pub unknown_callers: UnknownCallers,
+	/// Callers vchatd holds at once from any one address.
+	pub max_connections_per_address: usize,
+	/// Callers vchatd holds at once in total.
+	pub max_pending_callers: usize,
+	/// Notifications per caller per minute before vchatd mutes them.
+	pub notifications_per_minute: usize,
+	/// Longest text a peer can send, in bytes.
+	pub max_message_size: usize,
+	/// Longest name a peer can use, in characters.
+	pub max_name_length: usize,
+}
+
+//room for ids, replies and encryption around the text of a message
+const MESSAGE_OVERHEAD: usize = 1024;
+/// The default `max_message_size`.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+impl Default for Config {
+	fn default() -> Config{
+		Config {
+			heartbeat_interval: Duration::from_secs(15),
+			heartbeat_timeout: Duration::from_secs(45),
+			allow: vec![],
+			deny: vec![],
+			unknown_callers: UnknownCallers::default(),
+			max_connections_per_address: 3,
+			max_pending_callers: 20,
+			notifications_per_minute: 10,
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+			max_name_length: 64,
+		}
+	}
+}
+
+impl Config {
+	/// Loads the config file, falling back to defaults if there isnt one.
+	pub fn load() -> io::Result<Config>{
+		let mut config = Config::default();
+		let Some(path) = config_dir().map(|dir| dir.join("config")) else {return Ok(config)};
+		let contents = match fs::read_to_string(&path) {
+			Ok(contents) => contents,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(config),
+			Err(e) => return Err(e),
+		};
+		for (number,line) in contents.lines().enumerate(){
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {continue}
+			let Some((key,value)) = line.split_once('=') else {
+				return Err(config_error(&path,number,"expected key = value"));
+			};
+			config.set(key.trim(),value.trim()).map_err(|reason| config_error(&path,number,&reason))?;
+		}
+		Ok(config)
+	}
+	fn set(&mut self, key: &str, value: &str) -> Result<(),String>{
+		match key {
+			"heartbeat_interval" => self.heartbeat_interval = parse_seconds(value)?,
+			"heartbeat_timeout" => self.heartbeat_timeout = parse_seconds(value)?,
+			"allow" => self.allow.push(Rule::parse(value)?),
+			"deny" => self.deny.push(Rule::parse(value)?),
+			"unknown_callers" => self.unknown_callers = UnknownCallers::parse(value)?,
+			"max_connections_per_address" => self.max_connections_per_address = parse_count(value)?,
+			"max_pending_callers" => self.max_pending_callers = parse_count(value)?,
+			"notifications_per_minute" => self.notifications_per_minute = parse_count(value)?,
+			"max_message_size" => self.max_message_size = parse_count(value)?,
+			"max_name_length" => self.max_name_length = parse_count(value)?,
+			_ => return Err(format!("unknown setting \"{key}\"")),
+		}
+		Ok(())
+	}
+	/// Checks a caller against the `allow` and `deny` rules.
+	pub fn screen(&self, caller: &Caller) -> Verdict{
+		screen(&self.allow,&self.deny,caller)
+	}
+	/// Largest frame payload to accept once the handshake is done.
+	pub fn max_frame_payload(&self) -> usize{
+		self.max_message_size.saturating_add(MESSAGE_OVERHEAD)
+	}
+	/// Largest frame payload to accept before then, nothing in a hello or handshake needs more.
+	pub fn max_handshake_payload(&self) -> usize{
+		self.max_frame_payload().min(DEFAULT_MAX_PAYLOAD)
+	}
+}
+
+/// `$XDG_CONFIG_HOME/vanillachat`, or `~/.config/vanillachat`.
+pub fn config_dir() -> Option<PathBuf>{
+	match env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("vanillachat")),
+		_ => env::home_dir().map(|home| home.join(".config").join("vanillachat")),
+	}
+}
+
+/// Where vchatd listens for vchat, `$XDG_RUNTIME_DIR/vanillachat/vchatd.socket`.
+///
+/// The runtime directory belongs to the user alone, unlike `/tmp`.
+pub fn socket_path() -> io::Result<PathBuf>{
+	match env::var_os("XDG_RUNTIME_DIR") {
+		Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("vanillachat").join("vchatd.socket")),
+		_ => Err(io::Error::new(io::ErrorKind::NotFound,"XDG_RUNTIME_DIR is not set, so there is nowhere private for the daemon's socket.")),
+	}
+}
+
+fn parse_seconds(value: &str) -> Result<Duration,String>{
+	match value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+		_ => Err(format!("\"{value}\" is not a whole number of seconds")),
+	}
+}
+
+fn parse_count(value: &str) -> Result<usize,String>{
+	match value.parse::<usize>() {
+		Ok(count) if count > 0 => Ok(count),
+		_ => Err(format!("\"{value}\" is not a whole number above 0")),
+	}
+}
+
+fn config_error(path: &Path, number: usize, reason: &str) -> io::Error{
+	io::Error::new(io::ErrorKind::InvalidData,format!("{}:{}: {reason}",path.display(),number+1))
+}
@@ -0,0 +1,182 @@
+//! Which callers vchatd lets through.
+//!
+//! `deny` rules always win. A caller matching an `allow` rule is let through,
+//! and anyone matching neither is an unknown caller, handled by the
+//! `unknown_callers` setting.
+use std::net::IpAddr;
+use crate::identity::fingerprint;
+
+/// One `allow` or `deny` line.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Rule {
+	/// An address, or a block of them like `192.168.1.0/24`.
+	Network {address: IpAddr, prefix: u8},
+	/// `name:<name>`, the name the caller claims in their hello.
+	Name(String),
+	/// `key:<fingerprint>`, as shown by vchat.
+	Key(String),
+}
+
+/// What to do with callers no rule mentions.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub enum UnknownCallers {
+	/// Hold them and notify as usual.
+	#[default]
+	Accept,
+	/// Hold them, but without notifications.
+	Quiet,
+	/// Drop them.
+	Deny,
+}
+
+/// What we know about a caller so far, more gets filled in as they connect.
+#[derive(Debug,Clone,Copy)]
+pub struct Caller<'a> {
+	pub address: IpAddr,
+	pub name: Option<&'a str>,
+	pub key: Option<&'a [u8]>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Verdict {
+	Allow,
+	Unknown,
+	Deny,
+}
+
+impl Rule {
+	pub fn parse(value: &str) -> Result<Rule,String>{
+		if let Some(name) = value.strip_prefix("name:") {
+			return Ok(Rule::Name(name.trim().to_string()));
+		}
+		if let Some(key) = value.strip_prefix("key:") {
+			return Ok(Rule::Key(key.trim().to_lowercase()));
+		}
+		let (address,prefix) = match value.split_once('/') {
+			Some((address,prefix)) => (address,Some(prefix)),
+			None => (value,None),
+		};
+		let address: IpAddr = address.parse()
+			.map_err(|_| format!("\"{value}\" is not an address, a network, name:<name> or key:<fingerprint>"))?;
+		let bits = if address.is_ipv4() {32} else {128};
+		let prefix = match prefix.map(|prefix| prefix.parse::<u8>()) {
+			None => bits,
+			Some(Ok(prefix)) if prefix <= bits => prefix,
+			Some(_) => return Err(format!("\"{value}\" has a bad prefix length")),
+		};
+		//callers get the same treatment in same_network, so ipv4 rules can be written either way
+		match address {
+			IpAddr::V6(v6) if prefix >= 96 && let Some(v4) = v6.to_ipv4_mapped() => Ok(Rule::Network {address: IpAddr::V4(v4), prefix: prefix - 96}),
+			_ => Ok(Rule::Network {address, prefix}),
+		}
+	}
+	/// Whether the rule applies, rules about things we dont know yet never do.
+	pub fn matches(&self, caller: &Caller) -> bool{
+		match self {
+			Rule::Network {address,prefix} => same_network(*address,caller.address,*prefix),
+			Rule::Name(name) => caller.name == Some(name.as_str()),
+			Rule::Key(key) => caller.key.is_some_and(|caller_key| fingerprint(caller_key) == *key),
+		}
+	}
+}
+
+impl UnknownCallers {
+	pub fn parse(value: &str) -> Result<UnknownCallers,String>{
+		match value {
+			"accept" => Ok(UnknownCallers::Accept),
+			"quiet" => Ok(UnknownCallers::Quiet),
+			"deny" => Ok(UnknownCallers::Deny),
+			_ => Err(format!("\"{value}\" should be accept, quiet or deny")),
+		}
+	}
+}
+
+/// Checks a caller against the rules.
+pub fn screen(allow: &[Rule], deny: &[Rule], caller: &Caller) -> Verdict{
+	if deny.iter().any(|rule| rule.matches(caller)) {
+		Verdict::Deny
+	}else if allow.iter().any(|rule| rule.matches(caller)) {
+		Verdict::Allow
+	}else{
+		Verdict::Unknown
+	}
+}
+
+fn same_network(network: IpAddr, address: IpAddr, prefix: u8) -> bool{
+	//treat ipv4 mapped ipv6 addresses as the ipv4 address they are
+	let address = match address {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+		v4 => v4,
+	};
+	match (network,address) {
+		(IpAddr::V4(network),IpAddr::V4(address)) => {
+			let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+			u32::from(network) & mask == u32::from(address) & mask
+		},
+		(IpAddr::V6(network),IpAddr::V6(address)) => {
+			let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+			u128::from(network) & mask == u128::from(address) & mask
+		},
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn network(value: &str) -> Rule{
+		Rule::parse(value).unwrap()
+	}
+	fn from(address: &str) -> Caller<'static>{
+		Caller {address: address.parse().unwrap(), name: None, key: None}
+	}
+
+	#[test]
+	fn whole_internet(){
+		assert!(network("0.0.0.0/0").matches(&from("203.0.113.9")));
+		assert!(network("::/0").matches(&from("2001:db8::1")));
+		//ipv6 rules never match ipv4 callers, even /0
+		assert!(!network("::/0").matches(&from("203.0.113.9")));
+	}
+
+	#[test]
+	fn single_address(){
+		assert!(network("192.168.1.20/32").matches(&from("192.168.1.20")));
+		assert!(!network("192.168.1.20/32").matches(&from("192.168.1.21")));
+		assert_eq!(network("192.168.1.20"),network("192.168.1.20/32"));
+		assert!(network("2001:db8::1/128").matches(&from("2001:db8::1")));
+		assert!(!network("2001:db8::1/128").matches(&from("2001:db8::2")));
+	}
+
+	#[test]
+	fn networks(){
+		assert!(network("192.168.1.0/24").matches(&from("192.168.1.255")));
+		assert!(!network("192.168.1.0/24").matches(&from("192.168.2.1")));
+		assert!(network("2001:db8::/32").matches(&from("2001:db8:ffff::1")));
+		assert!(!network("2001:db8::/32").matches(&from("2001:db9::1")));
+	}
+
+	#[test]
+	fn ipv4_mapped_ipv6(){
+		//callers on a dual stack socket
+		assert!(network("192.168.1.0/24").matches(&from("::ffff:192.168.1.7")));
+		assert!(!network("192.168.1.0/24").matches(&from("::ffff:192.168.2.7")));
+		//rules written the same way
+		assert_eq!(network("::ffff:192.168.1.0/120"),network("192.168.1.0/24"));
+		assert!(network("::ffff:192.168.1.7").matches(&from("192.168.1.7")));
+	}
+
+	#[test]
+	fn bad_rules(){
+		for value in ["192.168.1.0/33","2001:db8::/129","192.168.1.0/","192.168.1.0/-1","192.168.1.0/x","192.168.1","bob"]{
+			assert!(Rule::parse(value).is_err(),"{value} should not parse");
+		}
+	}
+
+	#[test]
+	fn names_and_keys(){
+		assert_eq!(network("name: bob"),Rule::Name("bob".into()));
+		assert_eq!(network("key:AB:CD"),Rule::Key("ab:cd".into()));
+	}
+}
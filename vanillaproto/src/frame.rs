@@ -0,0 +1,281 @@
+//! Length prefixed frames.
+//!
+//! Every frame starts with a 6 byte header:
+//! ```text
+//! +---------+------+----------------+
+//! | version | type | length (u32 BE)|
+//! +---------+------+----------------+
+//! ```
+//! followed by `length` bytes of payload. Frames longer than the reader's
+//! limit are refused from the header alone, before any payload is buffered.
+use std::io;
+use std::io::{Read,Write,ErrorKind};
+use std::net::TcpStream;
+use std::time::Duration;
+
+//2 added encryption, version 1 peers send everything in the clear
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const HEADER_LENGTH: usize = 6;
+/// Largest payload accepted until the config says otherwise, plenty for hellos and handshakes.
+pub const DEFAULT_MAX_PAYLOAD: usize = 64 * 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FrameType {
+	Hello,
+	Text,
+	//why the sender is about to close the connection
+	Error,
+	Control,
+	Presence,
+	System,
+	//noise handshake messages, see crate::secure
+	Handshake,
+	//another frame, encrypted
+	Sealed,
+	//sent by newer peers, ignored by us
+	Unknown(u8),
+}
+
+#[derive(Debug,Clone)]
+pub struct Frame {
+	pub kind: FrameType,
+	pub payload: Vec<u8>,
+}
+
+/// Collects bytes from a nonblocking stream until whole frames are available.
+#[derive(Debug)]
+pub struct FrameBuffer {
+	buffer: Vec<u8>,
+	max_payload: usize,
+}
+
+impl FrameType {
+	pub fn from_byte(byte: u8) -> FrameType{
+		match byte {
+			1 => FrameType::Hello,
+			2 => FrameType::Text,
+			3 => FrameType::Error,
+			4 => FrameType::Control,
+			5 => FrameType::Presence,
+			6 => FrameType::System,
+			7 => FrameType::Handshake,
+			8 => FrameType::Sealed,
+			other => FrameType::Unknown(other),
+		}
+	}
+	pub fn to_byte(self) -> u8{
+		match self {
+			FrameType::Hello => 1,
+			FrameType::Text => 2,
+			FrameType::Error => 3,
+			FrameType::Control => 4,
+			FrameType::Presence => 5,
+			FrameType::System => 6,
+			FrameType::Handshake => 7,
+			FrameType::Sealed => 8,
+			FrameType::Unknown(other) => other,
+		}
+	}
+}
+
+impl Frame {
+	pub fn new(kind: FrameType, payload: Vec<u8>) -> Frame{
+		Frame {kind, payload}
+	}
+	/// The payload decoded as UTF-8.
+	///
+	/// Invalid sequences are replaced with U+FFFD rather than failing the whole
+	/// message, so one bad byte from a peer never loses the rest of the text.
+	/// Frames are only decoded once complete, so a character split across reads
+	/// is never seen half finished.
+	pub fn text(&self) -> String{
+		String::from_utf8_lossy(&self.payload).into_owned()
+	}
+}
+
+impl FrameBuffer {
+	pub fn new() -> FrameBuffer{
+		FrameBuffer {buffer: vec![], max_payload: DEFAULT_MAX_PAYLOAD}
+	}
+	/// Frames with a longer payload than this are an error.
+	pub fn set_max_payload(&mut self, max_payload: usize){
+		self.max_payload = max_payload;
+	}
+	pub fn extend(&mut self, bytes: &[u8]){
+		self.buffer.extend_from_slice(bytes);
+	}
+	/// Bytes received that dont make up a whole frame yet.
+	pub fn pending(&self) -> &[u8]{
+		&self.buffer
+	}
+	/// Pops the next complete frame, or `None` if more bytes are needed.
+	pub fn next_frame(&mut self) -> io::Result<Option<Frame>>{
+		//check the version as soon as we have it so old peers fail fast
+		if let Some(version) = self.buffer.first(){
+			check_version(*version)?;
+		}
+		if self.buffer.len() < HEADER_LENGTH {
+			return Ok(None);
+		}
+		let length = payload_length(&self.buffer[..HEADER_LENGTH]);
+		check_length(length,self.max_payload)?;
+		if self.buffer.len() < HEADER_LENGTH + length {
+			return Ok(None);
+		}
+		let kind = FrameType::from_byte(self.buffer[1]);
+		let payload = self.buffer[HEADER_LENGTH..HEADER_LENGTH+length].to_vec();
+		self.buffer.drain(..HEADER_LENGTH+length);
+		Ok(Some(Frame::new(kind,payload)))
+	}
+	/// Waits up to `timeout` for the next frame from a blocking stream.
+	///
+	/// Returns `None` if the time ran out first, any partial frame is kept for the next call.
+	pub fn read_from(&mut self, stream: &TcpStream, timeout: Duration) -> io::Result<Option<Frame>>{
+		if let Some(frame) = self.next_frame()? {
+			return Ok(Some(frame));
+		}
+		//a zero timeout means forever to set_read_timeout
+		stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+		let mut buffer = [0; 1024];
+		let result = (&*stream).read(&mut buffer);
+		stream.set_read_timeout(None)?;
+		match result {
+			Ok(0) => Err(io::Error::from(ErrorKind::UnexpectedEof)),
+			Ok(count) => {
+				self.extend(&buffer[..count]);
+				self.next_frame()
+			},
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Default for FrameBuffer {
+	fn default() -> FrameBuffer{
+		FrameBuffer::new()
+	}
+}
+
+/// Blocks until a whole frame has been read.
+pub fn read_frame<T: Read>(stream: &mut T, max_payload: usize) -> io::Result<Frame>{
+	let mut header = [0; HEADER_LENGTH];
+	//read the version byte on its own so old peers are caught before we wait for a full header
+	stream.read_exact(&mut header[..1])?;
+	check_version(header[0])?;
+	stream.read_exact(&mut header[1..])?;
+	let length = payload_length(&header);
+	check_length(length,max_payload)?;
+	let mut payload = vec![0; length];
+	stream.read_exact(&mut payload)?;
+	Ok(Frame::new(FrameType::from_byte(header[1]),payload))
+}
+
+/// [`read_frame`], but a peer whose frame is refused gets an `Error` frame saying why.
+pub fn read_frame_or_refuse<T: Read + Write>(stream: &mut T, max_payload: usize) -> io::Result<Frame>{
+	read_frame(stream,max_payload).inspect_err(|e| {
+		if e.kind() == ErrorKind::InvalidData {
+			let _ = write_frame(stream,FrameType::Error,e.to_string().as_bytes());
+		}
+	})
+}
+
+pub fn write_frame<T: Write>(stream: &mut T, kind: FrameType, payload: &[u8]) -> io::Result<()>{
+	let length: u32 = payload.len().try_into()
+		.map_err(|_| io::Error::new(ErrorKind::InvalidInput,"Frame payload too large."))?;
+	//build the whole frame first so it goes out in a single write
+	let mut bytes = Vec::with_capacity(HEADER_LENGTH + payload.len());
+	bytes.push(PROTOCOL_VERSION);
+	bytes.push(kind.to_byte());
+	bytes.extend_from_slice(&length.to_be_bytes());
+	bytes.extend_from_slice(payload);
+	stream.write_all(&bytes)
+}
+
+fn payload_length(header: &[u8]) -> usize{
+	u32::from_be_bytes([header[2],header[3],header[4],header[5]]) as usize
+}
+
+fn check_length(length: usize, max_payload: usize) -> io::Result<()>{
+	match length > max_payload {
+		true => Err(io::Error::new(ErrorKind::InvalidData,format!("A {length} byte frame is over the {max_payload} byte limit."))),
+		false => Ok(()),
+	}
+}
+
+fn check_version(version: u8) -> io::Result<()>{
+	if version == PROTOCOL_VERSION {
+		Ok(())
+	}else if version == 0x04 || version >= 0x20 {
+		//old clients start with their name as plain text, terminated by 0x04
+		Err(io::Error::new(ErrorKind::InvalidData,"Peer is using the old 0x04 terminated protocol, they need to update vanillachat."))
+	}else{
+		Err(io::Error::new(ErrorKind::InvalidData,format!("Peer speaks protocol version {version}, we only speak version {PROTOCOL_VERSION}.")))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame_bytes(kind: FrameType, payload: &[u8]) -> Vec<u8>{
+		let mut bytes = vec![];
+		write_frame(&mut bytes,kind,payload).unwrap();
+		bytes
+	}
+
+	#[test]
+	fn frames_split_across_reads(){
+		let mut bytes = frame_bytes(FrameType::Text,b"hello");
+		bytes.extend(frame_bytes(FrameType::Control,b""));
+		let mut frames = FrameBuffer::new();
+		for byte in &bytes[..8]{
+			assert!(frames.next_frame().unwrap().is_none());
+			frames.extend(&[*byte]);
+		}
+		frames.extend(&bytes[8..]);
+		let first = frames.next_frame().unwrap().unwrap();
+		assert_eq!((first.kind,first.payload.as_slice()),(FrameType::Text,&b"hello"[..]));
+		let second = frames.next_frame().unwrap().unwrap();
+		assert_eq!((second.kind,second.payload.len()),(FrameType::Control,0));
+		assert!(frames.next_frame().unwrap().is_none());
+	}
+
+	#[test]
+	fn oversized_frames_are_refused_from_the_header(){
+		let mut frames = FrameBuffer::new();
+		frames.set_max_payload(4);
+		frames.extend(&frame_bytes(FrameType::Text,b"hello")[..HEADER_LENGTH]);
+		assert_eq!(frames.next_frame().unwrap_err().kind(),ErrorKind::InvalidData);
+		let mut frames = FrameBuffer::new();
+		frames.set_max_payload(5);
+		frames.extend(&frame_bytes(FrameType::Text,b"hello"));
+		assert!(frames.next_frame().unwrap().is_some());
+	}
+
+	#[test]
+	fn read_frame_limit(){
+		let bytes = frame_bytes(FrameType::Text,b"hello");
+		assert_eq!(read_frame(&mut &bytes[..],4).unwrap_err().kind(),ErrorKind::InvalidData);
+		assert_eq!(read_frame(&mut &bytes[..],5).unwrap().payload,b"hello");
+	}
+
+	#[test]
+	fn wrong_version(){
+		let mut frames = FrameBuffer::new();
+		frames.extend(&[1]);
+		assert!(frames.next_frame().is_err());
+		assert!(read_frame(&mut &[PROTOCOL_VERSION + 1,2,0,0,0,0][..],DEFAULT_MAX_PAYLOAD).is_err());
+	}
+
+	#[test]
+	fn refused_frames_get_a_reason(){
+		let mut stream = io::Cursor::new(frame_bytes(FrameType::Hello,b"hello"));
+		assert!(read_frame_or_refuse(&mut stream,4).is_err());
+		//only the header was read, the reply is written over the rest
+		let written = stream.into_inner();
+		let reply = read_frame(&mut &written[HEADER_LENGTH..],DEFAULT_MAX_PAYLOAD).unwrap();
+		assert_eq!(reply.kind,FrameType::Error);
+		assert_eq!(reply.text(),"A 5 byte frame is over the 4 byte limit.");
+	}
+}
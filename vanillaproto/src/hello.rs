@@ -0,0 +1,215 @@
+//! The hello exchange both sides perform straight after connecting.
+//!
+//! Each side sends a [`Hello`] frame, then reads the other's. If the peer cant
+//! be talked to, an `Error` frame with the reason is sent back before closing,
+//! so the other end can tell its user why.
+use std::io;
+use std::io::{Read,Write,ErrorKind};
+use std::fmt;
+use crate::frame::{Frame,FrameType,read_frame_or_refuse,write_frame,PROTOCOL_VERSION};
+use crate::config::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::wire::{Encoder,Decoder};
+
+/// Optional features, only used when both sides advertise them.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Capabilities(pub u32);
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Hello {
+	pub protocol_version: u8,
+	pub software_version: String,
+	pub name: String,
+	pub capabilities: Capabilities,
+	/// Longest text the sender will take, in bytes, see [`Hello::max_message_size`].
+	pub max_message_size: u32,
+}
+
+impl Capabilities {
+	pub const NONE: Capabilities = Capabilities(0);
+	/// Delivery and read receipts for text messages.
+	pub const RECEIPTS: Capabilities = Capabilities(1 << 0);
+	/// Typing indicators.
+	pub const TYPING: Capabilities = Capabilities(1 << 1);
+	/// Ping and pong to spot peers that vanished without closing the connection.
+	pub const HEARTBEAT: Capabilities = Capabilities(1 << 2);
+	/// Editing and deleting sent texts.
+	pub const EDIT: Capabilities = Capabilities(1 << 3);
+	/// Texts that quote an earlier one.
+	pub const REPLIES: Capabilities = Capabilities(1 << 4);
+	/// Comparing short authentication strings with `/verify`.
+	pub const VERIFY: Capabilities = Capabilities(1 << 5);
+	/// Set by whoever was started with `--passphrase`, both sides need it or neither.
+	pub const PASSPHRASE: Capabilities = Capabilities(1 << 6);
+	/// Everything this build knows how to do.
+	pub const SUPPORTED: Capabilities = Capabilities(
+		Capabilities::RECEIPTS.0 | Capabilities::TYPING.0 | Capabilities::HEARTBEAT.0
+			| Capabilities::EDIT.0 | Capabilities::REPLIES.0 | Capabilities::VERIFY.0
+	);
+	//names for printing, one per bit
+	const NAMES: &'static [(Capabilities,&'static str)] = &[
+		(Capabilities::RECEIPTS,"receipts"),
+		(Capabilities::TYPING,"typing"),
+		(Capabilities::HEARTBEAT,"heartbeat"),
+		(Capabilities::EDIT,"editing"),
+		(Capabilities::REPLIES,"replies"),
+		(Capabilities::VERIFY,"verification"),
+		(Capabilities::PASSPHRASE,"passphrase"),
+	];
+
+	pub fn contains(self, other: Capabilities) -> bool{
+		self.0 & other.0 == other.0
+	}
+	pub fn intersection(self, other: Capabilities) -> Capabilities{
+		Capabilities(self.0 & other.0)
+	}
+	pub fn union(self, other: Capabilities) -> Capabilities{
+		Capabilities(self.0 | other.0)
+	}
+}
+
+impl fmt::Display for Capabilities {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+		let names: Vec<&str> = Capabilities::NAMES.iter()
+			.filter(|(capability,_)| self.contains(*capability))
+			.map(|(_,name)| *name)
+			.collect();
+		if names.is_empty() {
+			write!(f,"none")
+		}else{
+			write!(f,"{}",names.join(", "))
+		}
+	}
+}
+
+impl Hello {
+	pub fn new(name: &str, software_version: &str) -> Hello{
+		Hello {
+			protocol_version: PROTOCOL_VERSION,
+			software_version: software_version.to_string(),
+			name: name.to_string(),
+			capabilities: Capabilities::SUPPORTED,
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE as u32,
+		}
+	}
+	pub fn encode(&self) -> Vec<u8>{
+		Encoder::new()
+			.u8(self.protocol_version)
+			.string(&self.software_version)
+			.string(&self.name)
+			.u32(self.capabilities.0)
+			.u32(self.max_message_size)
+			.finish()
+	}
+	pub fn decode(payload: &[u8]) -> io::Result<Hello>{
+		let mut decoder = Decoder::new(payload);
+		Ok(Hello {
+			protocol_version: decoder.u8()?,
+			software_version: decoder.string()?,
+			name: decoder.string()?,
+			capabilities: Capabilities(decoder.u32()?),
+			//left off by older peers, who take the default
+			max_message_size: match decoder.is_empty() {
+				true => DEFAULT_MAX_MESSAGE_SIZE as u32,
+				false => decoder.u32()?,
+			},
+		})
+	}
+	/// The reason we cant talk to this peer, if there is one.
+	pub fn incompatibility(&self, theirs: &Hello) -> Option<String>{
+		if theirs.protocol_version != self.protocol_version {
+			return Some(format!(
+				"Peer speaks protocol version {} (vanillachat {}), we speak version {} (vanillachat {}).",
+				theirs.protocol_version,theirs.software_version,self.protocol_version,self.software_version,
+			));
+		}
+		match (self.capabilities.contains(Capabilities::PASSPHRASE),theirs.capabilities.contains(Capabilities::PASSPHRASE)) {
+			(true,false) => Some("We need a passphrase, but the peer was not started with --passphrase.".into()),
+			(false,true) => Some("Peer needs a passphrase, start with --passphrase to use one.".into()),
+			_ => None,
+		}
+	}
+	/// Features both of us support.
+	pub fn negotiate(&self, theirs: &Hello) -> Capabilities{
+		self.capabilities.intersection(theirs.capabilities)
+	}
+	/// Longest text both of us will take, anything longer isnt worth sending.
+	pub fn max_message_size(&self, theirs: &Hello) -> usize{
+		self.max_message_size.min(theirs.max_message_size) as usize
+	}
+}
+
+/// Sends our hello and waits for theirs.
+pub fn exchange_hello<T: Read + Write>(stream: &mut T, ours: &Hello, max_name_length: usize, max_payload: usize) -> io::Result<Hello>{
+	write_frame(stream,FrameType::Hello,&ours.encode())?;
+	let frame = read_frame_or_refuse(stream,max_payload)?;
+	let theirs = match frame.kind {
+		FrameType::Hello => Hello::decode(&frame.payload)?,
+		_ => return Err(unexpected_frame(&frame,"a hello")),
+	};
+	check_hello(stream,ours,&theirs,max_name_length)?;
+	Ok(theirs)
+}
+
+/// Refuses the peer with a readable reason if we cant talk to them.
+pub fn check_hello<T: Write>(stream: &mut T, ours: &Hello, theirs: &Hello, max_name_length: usize) -> io::Result<()>{
+	match ours.incompatibility(theirs).or_else(|| check_name(&theirs.name,max_name_length)) {
+		Some(reason) => {
+			let _ = write_frame(stream,FrameType::Error,reason.as_bytes());
+			Err(io::Error::new(ErrorKind::InvalidData,reason))
+		},
+		None => Ok(()),
+	}
+}
+
+/// The reason a peer cant use a name, if there is one.
+pub fn check_name(name: &str, max_name_length: usize) -> Option<String>{
+	let length = name.chars().count();
+	if name.is_empty() {
+		Some("Names cant be empty.".into())
+	}else if length > max_name_length {
+		Some(format!("A {length} character name is over the {max_name_length} character limit."))
+	}else if name.chars().any(char::is_control) {
+		Some("Names cant have control characters or line breaks in them.".into())
+	}else if name.trim() != name {
+		Some("Names cant start or end with spaces.".into())
+	}else{
+		None
+	}
+}
+
+/// Turns an unexpected frame into an error, passing on the peer's reason if it sent one.
+pub fn unexpected_frame(frame: &Frame, expected: &str) -> io::Error{
+	match frame.kind {
+		FrameType::Error => io::Error::new(ErrorKind::ConnectionRefused,format!("Peer refused the connection: {}",frame.text())),
+		kind => io::Error::new(ErrorKind::InvalidData,format!("Expected {expected} but got a {kind:?} frame.")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn message_size_limits(){
+		let mut ours = Hello::new("alice","0.1.0");
+		ours.max_message_size = 100;
+		let theirs = Hello::decode(&Hello::new("bob","0.1.0").encode()).unwrap();
+		assert_eq!(Hello::decode(&ours.encode()).unwrap(),ours);
+		assert_eq!((ours.max_message_size(&theirs),theirs.max_message_size(&ours)),(100,100));
+		//a hello without one
+		let older = Encoder::new().u8(PROTOCOL_VERSION).string("0.1.0").string("carol").u32(0).finish();
+		assert_eq!(Hello::decode(&older).unwrap().max_message_size,DEFAULT_MAX_MESSAGE_SIZE as u32);
+	}
+
+	#[test]
+	fn names(){
+		assert_eq!(check_name("alice",64),None);
+		assert_eq!(check_name("alice smith",64),None);
+		assert!(check_name("",64).is_some());
+		assert!(check_name("alice",4).is_some());
+		assert!(check_name("alice\nmallory",64).is_some());
+		assert!(check_name("alice\x1b[2J",64).is_some());
+		assert!(check_name(" alice",64).is_some());
+		assert!(check_name("alice\t",64).is_some());
+	}
+}
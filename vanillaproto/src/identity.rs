@@ -0,0 +1,294 @@
+//! Long term keys, and the keys we have seen peers use before.
+//!
+//! Each user has one key pair in `~/.config/vanillachat/identity`, shared by
+//! vchat and vchatd so a call picked up from the daemon looks the same to the
+//! peer. Peer keys are pinned the first time they are seen in
+//! `~/.config/vanillachat/known_peers`, one `key name` pair per line in the
+//! style of ssh's known_hosts, with `verified` on the end once the users have
+//! compared a [`short_authentication_string`]. Names are whatever the peer
+//! claimed, so both are written as hex and a name cant add lines of its own,
+//! which is why a changed key warning says which line to remove.
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Write,ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use blake2::{Blake2s256,Digest};
+use crate::config::config_dir;
+use crate::secure::{Keypair,generate_keypair};
+
+/// What the known peers file says about a key.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Trust {
+	/// It matches the key pinned for that name.
+	Known,
+	/// It matches, and the users have compared codes to prove it.
+	Verified,
+	/// Nothing is pinned for that name yet.
+	New,
+	/// A different key is pinned for that name, someone may be pretending to be them.
+	Changed {pinned: Vec<u8>, location: String},
+}
+
+#[derive(Debug,Default)]
+pub struct KnownPeers {
+	path: Option<PathBuf>,
+	peers: Vec<KnownPeer>,
+	//lines in the file, so pinned keys know which line they went on
+	lines: usize,
+	//lines that couldnt be read, for the user to be told about
+	skipped: Vec<String>,
+}
+
+#[derive(Debug)]
+struct KnownPeer {
+	name: String,
+	key: Vec<u8>,
+	verified: bool,
+	//where it is in the file, counting from 1
+	line: usize,
+}
+
+//what each 6 bits of a short authentication string are read out as
+const SAS_EMOJI: [(&str,&str); 64] = [
+	("🐶","dog"),("🐱","cat"),("🦁","lion"),("🐎","horse"),("🦄","unicorn"),("🐷","pig"),("🐘","elephant"),("🐰","rabbit"),
+	("🐼","panda"),("🐓","rooster"),("🐧","penguin"),("🐢","turtle"),("🐟","fish"),("🐙","octopus"),("🦋","butterfly"),("🌷","flower"),
+	("🌳","tree"),("🌵","cactus"),("🍄","mushroom"),("🌏","globe"),("🌙","moon"),("☁","cloud"),("🔥","fire"),("🍌","banana"),
+	("🍎","apple"),("🍓","strawberry"),("🌽","corn"),("🍕","pizza"),("🎂","cake"),("❤","heart"),("😀","smiley"),("🤖","robot"),
+	("🎩","hat"),("👓","glasses"),("🔧","spanner"),("🎅","santa"),("👍","thumbs up"),("☂","umbrella"),("⌛","hourglass"),("⏰","clock"),
+	("🎁","gift"),("💡","light bulb"),("📕","book"),("✏","pencil"),("📎","paperclip"),("✂","scissors"),("🔒","lock"),("🔑","key"),
+	("🔨","hammer"),("☎","telephone"),("🏁","flag"),("🚂","train"),("🚲","bicycle"),("✈","aeroplane"),("🚀","rocket"),("🏆","trophy"),
+	("⚽","ball"),("🎸","guitar"),("🎺","trumpet"),("🔔","bell"),("⚓","anchor"),("🎧","headphones"),("📁","folder"),("📌","pin"),
+];
+const SAS_LENGTH: usize = 7;
+
+/// Loads our key pair, making one the first time.
+pub fn load_identity() -> io::Result<Keypair>{
+	let Some(dir) = config_dir() else {
+		return Err(io::Error::new(ErrorKind::NotFound,"Could not find a config directory to keep our identity in."));
+	};
+	let path = dir.join("identity");
+	match fs::read_to_string(&path) {
+		Ok(contents) => {
+			let mut lines = contents.lines().map(from_hex);
+			match (lines.next().flatten(),lines.next().flatten()) {
+				(Some(private),Some(public)) => Ok(Keypair {private, public}),
+				_ => Err(io::Error::new(ErrorKind::InvalidData,format!("{} is corrupt.",path.display()))),
+			}
+		},
+		Err(ref e) if e.kind() == ErrorKind::NotFound => {
+			let keypair = generate_keypair()?;
+			fs::create_dir_all(&dir)?;
+			//only we get to read the private key
+			let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+			file.write_all(format!("{}\n{}\n",to_hex(&keypair.private),to_hex(&keypair.public)).as_bytes())?;
+			Ok(keypair)
+		},
+		Err(e) => Err(e),
+	}
+}
+
+/// A short, readable summary of a public key.
+pub fn fingerprint(public: &[u8]) -> String{
+	let hash = Blake2s256::digest(public);
+	hash[..16].iter().map(|byte| format!("{byte:02x}")).collect::<Vec<String>>().join(":")
+}
+
+/// Seven emoji, with names to read out, that both ends of a connection see
+/// the same only if nobody is sitting in the middle of it.
+pub fn short_authentication_string(handshake_hash: &[u8]) -> String{
+	let hash = Blake2s256::new()
+		.chain_update(b"vanillachat short authentication string")
+		.chain_update(handshake_hash)
+		.finalize();
+	//6 bits per emoji, out of the first 48 bits
+	let bits = u64::from_be_bytes([0,0,hash[0],hash[1],hash[2],hash[3],hash[4],hash[5]]);
+	(0..SAS_LENGTH)
+		.map(|i| SAS_EMOJI[(bits >> (42 - 6*i)) as usize & 0x3f])
+		.map(|(emoji,name)| format!("{emoji} {name}"))
+		.collect::<Vec<String>>()
+		.join("  ")
+}
+
+impl KnownPeers {
+	/// Reads the known peers file, starting empty if there isnt one.
+	pub fn load() -> io::Result<KnownPeers>{
+		match config_dir() {
+			Some(dir) => KnownPeers::read(dir.join("known_peers")),
+			None => Ok(KnownPeers::default()),
+		}
+	}
+	fn read(path: PathBuf) -> io::Result<KnownPeers>{
+		let contents = match fs::read_to_string(&path) {
+			Ok(contents) => contents,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+		let mut peers = vec![];
+		let mut skipped = vec![];
+		for (number,line) in contents.lines().enumerate(){
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {continue}
+			match parse_known_peer(line,number+1) {
+				Some(peer) => peers.push(peer),
+				//one bad line shouldnt lose everyone else
+				None => skipped.push(format!("{}:{}: expected key then name, skipping it",path.display(),number+1)),
+			}
+		}
+		Ok(KnownPeers {lines: contents.lines().count(), path: Some(path), peers, skipped})
+	}
+	/// Lines of the file that were skipped, and why.
+	pub fn warnings(&self) -> &[String]{
+		&self.skipped
+	}
+	pub fn check(&self, name: &str, key: &[u8]) -> Trust{
+		let pinned: Vec<&KnownPeer> = self.peers.iter()
+			.filter(|peer| peer.name == name)
+			.collect();
+		match (pinned.first(),pinned.iter().find(|peer| peer.key == key)) {
+			(None,_) => Trust::New,
+			(_,Some(peer)) if peer.verified => Trust::Verified,
+			(_,Some(_)) => Trust::Known,
+			(Some(first),None) => Trust::Changed {pinned: first.key.clone(), location: self.location(first.line)},
+		}
+	}
+	/// Remembers a key for a name, both here and in the file.
+	pub fn pin(&mut self, name: &str, key: &[u8]) -> io::Result<()>{
+		self.lines += 1;
+		self.peers.push(KnownPeer {name: name.to_string(), key: key.to_vec(), verified: false, line: self.lines});
+		let Some(path) = &self.path else {return Ok(())};
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+		file.write_all(format!("{}\n",known_peer_line(name,key,false)).as_bytes())
+	}
+	/// Marks a key as checked in person, replacing any other key pinned for the name.
+	pub fn verify(&mut self, name: &str, key: &[u8]) -> io::Result<()>{
+		self.peers.retain(|peer| peer.name != name);
+		self.peers.push(KnownPeer {name: name.to_string(), key: key.to_vec(), verified: true, line: 0});
+		let Some(path) = self.path.clone() else {return Ok(())};
+		//keep comments and everyone else as they were
+		let contents = match fs::read_to_string(&path) {
+			Ok(contents) => contents,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+		let mut lines: Vec<String> = contents.lines()
+			.filter(|line| parse_known_peer(line.trim(),0).is_none_or(|peer| peer.name != name))
+			.map(|line| line.to_string())
+			.collect();
+		lines.push(known_peer_line(name,key,true));
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&path,lines.join("\n") + "\n")?;
+		//everyone after the old line has moved up
+		*self = KnownPeers::read(path)?;
+		Ok(())
+	}
+	//a line of the file, as path:line
+	fn location(&self, line: usize) -> String{
+		match &self.path {
+			Some(path) => format!("{}:{line}",path.display()),
+			None => "known_peers".into(),
+		}
+	}
+}
+
+impl Trust {
+	/// The warning to show the user, if there is one.
+	pub fn warning(&self, name: &str, key: &[u8]) -> Option<String>{
+		match self {
+			Trust::Changed {pinned,location} => Some(format!(
+				"WARNING: <{name}> IS USING A DIFFERENT KEY THAN LAST TIME, SOMEONE MAY BE PRETENDING TO BE THEM.\n\
+				pinned fingerprint: {}\n\
+				their fingerprint:  {}\n\
+				if they really did change keys, remove the pinned key at {location}",
+				fingerprint(pinned),fingerprint(key),
+			)),
+			_ => None,
+		}
+	}
+}
+
+//key, name and then maybe "verified", the name as hex so nothing in it can break the line
+fn known_peer_line(name: &str, key: &[u8], verified: bool) -> String{
+	match verified {
+		true => format!("{} {} verified",to_hex(key),to_hex(name.as_bytes())),
+		false => format!("{} {}",to_hex(key),to_hex(name.as_bytes())),
+	}
+}
+
+fn parse_known_peer(line: &str, number: usize) -> Option<KnownPeer>{
+	let mut fields = line.split_whitespace();
+	let key = from_hex(fields.next()?)?;
+	let name = String::from_utf8(from_hex(fields.next()?)?).ok()?;
+	let verified = match fields.next() {
+		Some("verified") => true,
+		Some(_) => return None,
+		None => false,
+	};
+	match fields.next() {
+		Some(_) => None,
+		None => Some(KnownPeer {name, key, verified, line: number}),
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String{
+	bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>>{
+	let text = text.trim();
+	if !text.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i+2)?,16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_cant_add_lines(){
+		let name = "alice 00ff verified\nmallory";
+		let line = known_peer_line(name,&[0xab; 32],false);
+		assert!(!line.contains('\n'));
+		let peer = parse_known_peer(&line,1).unwrap();
+		assert_eq!(peer.name,name);
+		assert_eq!(peer.key,vec![0xab; 32]);
+		assert!(!peer.verified);
+	}
+
+	#[test]
+	fn verified_survives_a_round_trip(){
+		let peer = parse_known_peer(&known_peer_line("bob",&[1,2,3],true),1).unwrap();
+		assert_eq!((peer.name.as_str(),peer.verified),("bob",true));
+	}
+
+	#[test]
+	fn changed_keys_point_at_their_line(){
+		let path = std::env::temp_dir().join(format!("vanillachat-known-peers-{}",std::process::id()));
+		fs::write(&path,format!("# pinned by hand\n{}\n{}\n",known_peer_line("alice",&[1; 32],false),known_peer_line("bob",&[2; 32],true))).unwrap();
+		let mut known_peers = KnownPeers::read(path.clone()).unwrap();
+		assert_eq!(known_peers.check("bob",&[2; 32]),Trust::Verified);
+		assert_eq!(known_peers.check("bob",&[3; 32]),Trust::Changed {pinned: vec![2; 32], location: format!("{}:3",path.display())});
+		known_peers.pin("carol",&[4; 32]).unwrap();
+		known_peers.verify("alice",&[1; 32]).unwrap();
+		//alice moved to the end, so everyone after her moved up
+		assert_eq!(known_peers.check("bob",&[3; 32]),Trust::Changed {pinned: vec![2; 32], location: format!("{}:2",path.display())});
+		assert_eq!(known_peers.check("carol",&[3; 32]),Trust::Changed {pinned: vec![4; 32], location: format!("{}:3",path.display())});
+		fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn bad_lines_are_refused(){
+		assert!(parse_known_peer("alice abcdef",1).is_none());
+		assert!(parse_known_peer("abcd 616c696365 trusted",1).is_none());
+		assert!(parse_known_peer("abcd 616c696365 verified extra",1).is_none());
+		assert!(parse_known_peer("abcd",1).is_none());
+	}
+}
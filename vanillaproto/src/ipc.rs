@@ -0,0 +1,340 @@
+//! Requests to vchatd over its unix socket, see [`crate::socket_path`].
+//!
+//! Each connection carries one [`Request`] and its [`Response`], both sent as
+//! a u32 length followed by that many bytes, the first of which is
+//! [`IPC_VERSION`]. Taking a call is the one request answered with more than
+//! bytes: after [`Response::Taken`] comes a two byte `Ok` with the caller's
+//! socket attached as `SCM_RIGHTS`. The caller is only let go once vchat
+//! sends an `Ok` back, and vchat only uses the socket once the daemon answers
+//! that with a last `Ok`, so the two never both read it.
+use std::io;
+use std::io::{Read,Write,ErrorKind};
+use crate::frame::{Frame,FrameType};
+use crate::hello::Hello;
+use crate::message::Message;
+use crate::secure::Session;
+use crate::wire::{Encoder,Decoder};
+
+//1 replaced the fixed list then pick exchange
+pub const IPC_VERSION: u8 = 1;
+//the whole backlog of a caller fits with plenty of room
+const MAX_IPC_LENGTH: usize = 64 * 1024 * 1024;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Request {
+	/// Everyone on hold.
+	ListPending,
+	/// Hands a caller over, see [`Response::Taken`].
+	Take {id: u64},
+	/// Hangs up on a caller, telling them why.
+	Reject {id: u64, reason: String},
+	/// Sends a text to a caller who is still on hold.
+	SendToPending {id: u64, body: String},
+	Status,
+	/// Says goodbye to every caller and stops the daemon.
+	Shutdown,
+}
+
+pub enum Response {
+	Done,
+	Pending(Vec<Pending>),
+	/// Everything needed to carry on the call, the socket itself comes next.
+	Taken(Handover),
+	Status(Status),
+	/// Why the request failed, [`read_response`] turns this into an error.
+	Error {reason: String},
+}
+
+/// A caller on hold, as listed by [`Request::ListPending`].
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Pending {
+	/// Stays the same while they are on hold, unlike their place in the list.
+	pub id: u64,
+	/// Unix time they called at.
+	pub called: u64,
+	pub address: String,
+	/// Texts held for whoever picks up.
+	pub queued: u32,
+	pub peer: Hello,
+}
+
+/// The daemon's end of a call, for whoever picks it up.
+pub struct Handover {
+	pub session: Session,
+	/// Bytes of a frame that had not finished arriving.
+	pub pending: Vec<u8>,
+	/// Frames the daemon queued that the caller hasnt been sent yet, they go before anything else.
+	pub outgoing: Vec<u8>,
+	/// Texts sent with [`Request::SendToPending`], so ids carry on after them.
+	pub sent: u64,
+	/// Texts that arrived while on hold, with the unix time of each.
+	pub backlog: Vec<(u64,Message)>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Status {
+	pub software_version: String,
+	/// Callers on hold.
+	pub pending: u32,
+	/// Callers still in the hello or handshake.
+	pub connecting: u32,
+	/// Seconds since the daemon started.
+	pub uptime: u64,
+}
+
+//request kinds
+const LIST_PENDING: u8 = 1;
+const TAKE: u8 = 2;
+const REJECT: u8 = 3;
+const SEND_TO_PENDING: u8 = 4;
+const STATUS: u8 = 5;
+const SHUTDOWN: u8 = 6;
+//response kinds
+const DONE: u8 = 1;
+const PENDING: u8 = 2;
+const TAKEN: u8 = 3;
+const STATUS_REPORT: u8 = 4;
+const ERROR: u8 = 5;
+
+impl Request {
+	pub fn encode(&self) -> Vec<u8>{
+		let encoder = Encoder::new().u8(IPC_VERSION);
+		match self {
+			Request::ListPending => encoder.u8(LIST_PENDING),
+			Request::Take {id} => encoder.u8(TAKE).u64(*id),
+			Request::Reject {id,reason} => encoder.u8(REJECT).u64(*id).string(reason),
+			Request::SendToPending {id,body} => encoder.u8(SEND_TO_PENDING).u64(*id).string(body),
+			Request::Status => encoder.u8(STATUS),
+			Request::Shutdown => encoder.u8(SHUTDOWN),
+		}.finish()
+	}
+	pub fn decode(payload: &[u8]) -> io::Result<Request>{
+		let mut decoder = Decoder::new(payload);
+		check_version(decoder.u8()?)?;
+		Ok(match decoder.u8()? {
+			LIST_PENDING => Request::ListPending,
+			TAKE => Request::Take {id: decoder.u64()?},
+			REJECT => Request::Reject {id: decoder.u64()?, reason: decoder.string()?},
+			SEND_TO_PENDING => Request::SendToPending {id: decoder.u64()?, body: decoder.string()?},
+			STATUS => Request::Status,
+			SHUTDOWN => Request::Shutdown,
+			other => return Err(io::Error::new(ErrorKind::InvalidData,format!("Unknown request {other}."))),
+		})
+	}
+}
+
+impl Response {
+	pub fn encode(&self) -> Vec<u8>{
+		let encoder = Encoder::new().u8(IPC_VERSION);
+		match self {
+			Response::Done => encoder.u8(DONE),
+			Response::Pending(callers) => {
+				let mut encoder = encoder.u8(PENDING).u32(callers.len().try_into().unwrap_or(u32::MAX));
+				for caller in callers{
+					encoder = encoder.u64(caller.id)
+						.u64(caller.called)
+						.string(&caller.address)
+						.u32(caller.queued)
+						.bytes(&caller.peer.encode());
+				}
+				encoder
+			},
+			Response::Taken(handover) => {
+				let mut encoder = encoder.u8(TAKEN)
+					.bytes(&handover.session.encode())
+					.bytes(&handover.pending)
+					.bytes(&handover.outgoing)
+					.u64(handover.sent)
+					.u32(handover.backlog.len().try_into().unwrap_or(u32::MAX));
+				for (time,message) in &handover.backlog{
+					encoder = encoder.u64(*time).bytes(&message.encode().payload);
+				}
+				encoder
+			},
+			Response::Status(status) => encoder.u8(STATUS_REPORT)
+				.string(&status.software_version)
+				.u32(status.pending)
+				.u32(status.connecting)
+				.u64(status.uptime),
+			Response::Error {reason} => encoder.u8(ERROR).string(reason),
+		}.finish()
+	}
+	pub fn decode(payload: &[u8]) -> io::Result<Response>{
+		let mut decoder = Decoder::new(payload);
+		check_version(decoder.u8()?)?;
+		Ok(match decoder.u8()? {
+			DONE => Response::Done,
+			PENDING => {
+				let mut callers = vec![];
+				for _ in 0..decoder.u32()?{
+					callers.push(Pending {
+						id: decoder.u64()?,
+						called: decoder.u64()?,
+						address: decoder.string()?,
+						queued: decoder.u32()?,
+						peer: Hello::decode(&decoder.bytes()?)?,
+					});
+				}
+				Response::Pending(callers)
+			},
+			TAKEN => {
+				let session = Session::decode(&decoder.bytes()?)?;
+				let pending = decoder.bytes()?;
+				let outgoing = decoder.bytes()?;
+				let sent = decoder.u64()?;
+				let mut backlog = vec![];
+				for _ in 0..decoder.u32()?{
+					//only texts are kept, so thats what the payload is
+					backlog.push((decoder.u64()?,Message::decode(&Frame::new(FrameType::Text,decoder.bytes()?))?));
+				}
+				Response::Taken(Handover {session,pending,outgoing,sent,backlog})
+			},
+			STATUS_REPORT => Response::Status(Status {
+				software_version: decoder.string()?,
+				pending: decoder.u32()?,
+				connecting: decoder.u32()?,
+				uptime: decoder.u64()?,
+			}),
+			ERROR => Response::Error {reason: decoder.string()?},
+			other => return Err(io::Error::new(ErrorKind::InvalidData,format!("Unknown response {other}."))),
+		})
+	}
+}
+
+fn check_version(version: u8) -> io::Result<()>{
+	match version {
+		IPC_VERSION => Ok(()),
+		version => Err(io::Error::new(ErrorKind::InvalidData,format!(
+			"Got ipc version {version}, we speak version {IPC_VERSION}, are vchat and vchatd from the same release?"
+		))),
+	}
+}
+
+pub fn read_request<T: Read>(stream: &mut T) -> io::Result<Request>{
+	Request::decode(&read_length_prefixed(stream)?)
+}
+
+/// Pops a whole request off the front of `buffer`, or `None` if more bytes are needed.
+pub fn next_request(buffer: &mut Vec<u8>) -> io::Result<Option<Request>>{
+	if buffer.len() < 4 {
+		return Ok(None);
+	}
+	let length = u32::from_be_bytes([buffer[0],buffer[1],buffer[2],buffer[3]]) as usize;
+	check_length(length)?;
+	if buffer.len() < 4 + length {
+		return Ok(None);
+	}
+	let request = Request::decode(&buffer[4..4+length]);
+	buffer.drain(..4+length);
+	request.map(Some)
+}
+
+pub fn write_request<T: Write>(stream: &mut T, request: &Request) -> io::Result<()>{
+	write_length_prefixed(stream,&request.encode())
+}
+
+/// Reads the daemon's answer, with [`Response::Error`] as an `Err`.
+pub fn read_response<T: Read>(stream: &mut T) -> io::Result<Response>{
+	match Response::decode(&read_length_prefixed(stream)?)? {
+		Response::Error {reason} => Err(io::Error::other(reason)),
+		response => Ok(response),
+	}
+}
+
+pub fn write_response<T: Write>(stream: &mut T, response: &Response) -> io::Result<()>{
+	write_length_prefixed(stream,&response.encode())
+}
+
+fn read_length_prefixed<T: Read>(stream: &mut T) -> io::Result<Vec<u8>>{
+	let mut length_buffer = [0; 4];
+	stream.read_exact(&mut length_buffer)?;
+	let length = u32::from_be_bytes(length_buffer) as usize;
+	check_length(length)?;
+	let mut bytes = vec![0; length];
+	stream.read_exact(&mut bytes)?;
+	Ok(bytes)
+}
+
+fn check_length(length: usize) -> io::Result<()>{
+	match length > MAX_IPC_LENGTH {
+		true => Err(io::Error::new(ErrorKind::InvalidData,format!("A {length} byte ipc message is over the {MAX_IPC_LENGTH} byte limit."))),
+		false => Ok(()),
+	}
+}
+
+fn write_length_prefixed<T: Write>(stream: &mut T, bytes: &[u8]) -> io::Result<()>{
+	let mut message = Vec::with_capacity(bytes.len() + 4);
+	message.extend_from_slice(&u32::try_from(bytes.len()).unwrap_or(u32::MAX).to_be_bytes());
+	message.extend_from_slice(bytes);
+	stream.write_all(&message)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn round_trip(request: Request){
+		let mut bytes = vec![];
+		write_request(&mut bytes,&request).unwrap();
+		assert_eq!(read_request(&mut &bytes[..]).unwrap(),request);
+	}
+
+	#[test]
+	fn requests(){
+		round_trip(Request::ListPending);
+		round_trip(Request::Take {id: u64::MAX});
+		round_trip(Request::Reject {id: 3, reason: "busy".into()});
+		round_trip(Request::SendToPending {id: 4, body: "be right there".into()});
+		round_trip(Request::Status);
+		round_trip(Request::Shutdown);
+	}
+
+	#[test]
+	fn requests_split_across_reads(){
+		let mut bytes = vec![];
+		write_request(&mut bytes,&Request::Take {id: 5}).unwrap();
+		write_request(&mut bytes,&Request::Status).unwrap();
+		let mut buffer = vec![];
+		for byte in &bytes[..6]{
+			assert_eq!(next_request(&mut buffer).unwrap(),None);
+			buffer.push(*byte);
+		}
+		buffer.extend_from_slice(&bytes[6..]);
+		assert_eq!(next_request(&mut buffer).unwrap(),Some(Request::Take {id: 5}));
+		assert_eq!(next_request(&mut buffer).unwrap(),Some(Request::Status));
+		assert!(buffer.is_empty());
+		let mut length = (MAX_IPC_LENGTH as u32 + 1).to_be_bytes().to_vec();
+		assert!(next_request(&mut length).is_err());
+	}
+
+	#[test]
+	fn pending_callers(){
+		let caller = Pending {id: 7, called: 1_700_000_000, address: "127.0.0.1:4000".into(), queued: 2, peer: Hello::new("bob","0.1.0")};
+		let mut bytes = vec![];
+		write_response(&mut bytes,&Response::Pending(vec![caller.clone()])).unwrap();
+		match read_response(&mut &bytes[..]).unwrap() {
+			Response::Pending(callers) => assert_eq!(callers,vec![caller]),
+			_ => panic!("expected a list of callers"),
+		}
+	}
+
+	#[test]
+	fn errors_come_back_as_errors(){
+		let mut bytes = vec![];
+		write_response(&mut bytes,&Response::Error {reason: "Nobody with id 9 is on hold.".into()}).unwrap();
+		assert_eq!(read_response(&mut &bytes[..]).err().unwrap().to_string(),"Nobody with id 9 is on hold.");
+	}
+
+	#[test]
+	fn bad_requests(){
+		//another version
+		assert!(Request::decode(&[IPC_VERSION + 1,1]).is_err());
+		//unknown kind
+		assert!(Request::decode(&[IPC_VERSION,99]).is_err());
+		//cut short
+		assert!(Request::decode(&[IPC_VERSION,2,0,0]).is_err());
+		//too long to bother reading
+		let length = (MAX_IPC_LENGTH as u32 + 1).to_be_bytes();
+		assert!(read_request(&mut &length[..]).is_err());
+	}
+}
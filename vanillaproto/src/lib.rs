@@ -0,0 +1,19 @@
+//! Wire protocol shared by vchat and vchatd.
+pub mod frame;
+pub mod wire;
+pub mod hello;
+pub mod message;
+pub mod config;
+pub mod secure;
+pub mod identity;
+pub mod filter;
+pub mod ipc;
+
+pub use frame::{Frame,FrameType,FrameBuffer,read_frame,read_frame_or_refuse,write_frame,PROTOCOL_VERSION};
+pub use hello::{Hello,Capabilities,exchange_hello,check_hello,check_name,unexpected_frame};
+pub use message::{Message,Receipt,Reply,TYPING_REFRESH,TYPING_EXPIRY,read_message,write_message};
+pub use config::{Config,socket_path};
+pub use filter::{Rule,UnknownCallers,Caller,Verdict};
+pub use secure::{Session,Handshake,Keypair,CipherState,SealedWriter,generate_keypair,handshake,passphrase_key};
+pub use identity::{KnownPeers,Trust,load_identity,fingerprint,short_authentication_string};
+pub use ipc::{Request,Response,Pending,Handover,Status,IPC_VERSION,read_request,next_request,write_request,read_response,write_response};
@@ -0,0 +1,225 @@
+//! The typed envelope carried by frames once the hello exchange is done.
+//!
+//! Chat text, control traffic, presence and system notices each get their
+//! own frame type. Control and presence payloads start with a kind byte so
+//! new kinds can be added without a new frame type.
+use std::io;
+use std::io::{Read,Write,ErrorKind};
+use std::time::Duration;
+use crate::frame::{Frame,FrameType,read_frame,write_frame};
+use crate::hello::unexpected_frame;
+use crate::wire::{Encoder,Decoder};
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Message {
+	//====== text ======
+	/// Ids count up from 1 for each sender, and are what receipts refer to.
+	Text {id: u64, body: String, reply_to: Option<Reply>},
+	//====== control ======
+	Nick {name: String},
+	Ack {id: u64, receipt: Receipt},
+	/// Answered with a pong, proves the peer is still there.
+	Ping,
+	Pong,
+	/// Sent just before closing, so the peer knows it wasnt an error.
+	Goodbye {reason: String},
+	/// Replaces the body of one of the sender's earlier texts.
+	Edit {id: u64, body: String},
+	/// Withdraws one of the sender's earlier texts.
+	Delete {id: u64},
+	/// Asks the peer to show the short authentication string, or says that
+	/// the sender has confirmed it matches.
+	Verify {confirmed: bool},
+	//====== presence ======
+	/// Sent when the input buffer fills or empties, and repeated every
+	/// [`TYPING_REFRESH`] while it stays full.
+	Typing {typing: bool},
+	//====== system ======
+	/// Informational notice, not written by the peer's user.
+	System {text: String},
+	/// Something from a newer peer we dont understand, safe to ignore.
+	Unknown,
+}
+
+/// The earlier text a reply quotes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Reply {
+	/// Whether the sender of the reply wrote the quoted text, or its recipient did.
+	pub own: bool,
+	/// The id its author gave it.
+	pub id: u64,
+}
+
+/// How far a sent text message has got.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Receipt {
+	/// It reached the peer's machine.
+	Delivered,
+	/// The peer's terminal displayed it.
+	Read,
+}
+
+/// How often a typing peer repeats [`Message::Typing`].
+pub const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// How long a typing indicator lasts without being repeated.
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(6);
+
+//text extensions
+const REPLY: u8 = 1;
+//control kinds
+const NICK: u8 = 1;
+const ACK: u8 = 2;
+const PING: u8 = 3;
+const PONG: u8 = 4;
+const GOODBYE: u8 = 5;
+const EDIT: u8 = 6;
+const DELETE: u8 = 7;
+const VERIFY: u8 = 8;
+//presence kinds
+const TYPING: u8 = 1;
+
+impl Message {
+	pub fn encode(&self) -> Frame{
+		match self {
+			Message::Text {id,body,reply_to} => {
+				let mut encoder = Encoder::new().u64(*id).string(body);
+				//optional, older peers stop reading after the body
+				if let Some(reply) = reply_to {
+					encoder = encoder.u8(REPLY).u8(reply.own as u8).u64(reply.id);
+				}
+				Frame::new(FrameType::Text,encoder.finish())
+			},
+			Message::Nick {name} => Frame::new(FrameType::Control,Encoder::new().u8(NICK).string(name).finish()),
+			Message::Ack {id,receipt} => Frame::new(FrameType::Control,Encoder::new().u8(ACK).u64(*id).u8(receipt.to_byte()).finish()),
+			Message::Ping => Frame::new(FrameType::Control,vec![PING]),
+			Message::Pong => Frame::new(FrameType::Control,vec![PONG]),
+			Message::Goodbye {reason} => Frame::new(FrameType::Control,Encoder::new().u8(GOODBYE).string(reason).finish()),
+			Message::Edit {id,body} => Frame::new(FrameType::Control,Encoder::new().u8(EDIT).u64(*id).string(body).finish()),
+			Message::Delete {id} => Frame::new(FrameType::Control,Encoder::new().u8(DELETE).u64(*id).finish()),
+			Message::Verify {confirmed} => Frame::new(FrameType::Control,Encoder::new().u8(VERIFY).u8(*confirmed as u8).finish()),
+			Message::Typing {typing} => Frame::new(FrameType::Presence,Encoder::new().u8(TYPING).u8(*typing as u8).finish()),
+			Message::System {text} => Frame::new(FrameType::System,text.as_bytes().to_vec()),
+			Message::Unknown => Frame::new(FrameType::Unknown(0),vec![]),
+		}
+	}
+	pub fn decode(frame: &Frame) -> io::Result<Message>{
+		let mut decoder = Decoder::new(&frame.payload);
+		Ok(match frame.kind {
+			FrameType::Text => Message::Text {
+				id: decoder.u64()?,
+				body: decoder.string()?,
+				reply_to: match decoder.is_empty() {
+					true => None,
+					false => match decoder.u8()? {
+						REPLY => Some(Reply {own: decoder.u8()? != 0, id: decoder.u64()?}),
+						_ => None,
+					},
+				},
+			},
+			FrameType::Control => match decoder.u8()? {
+				NICK => Message::Nick {name: decoder.string()?},
+				ACK => match (decoder.u64()?,Receipt::from_byte(decoder.u8()?)) {
+					(id,Some(receipt)) => Message::Ack {id,receipt},
+					(_,None) => Message::Unknown,
+				},
+				PING => Message::Ping,
+				PONG => Message::Pong,
+				GOODBYE => Message::Goodbye {reason: decoder.string()?},
+				EDIT => Message::Edit {id: decoder.u64()?, body: decoder.string()?},
+				DELETE => Message::Delete {id: decoder.u64()?},
+				VERIFY => Message::Verify {confirmed: decoder.u8()? != 0},
+				_ => Message::Unknown,
+			},
+			FrameType::Presence => match decoder.u8()? {
+				TYPING => Message::Typing {typing: decoder.u8()? != 0},
+				_ => Message::Unknown,
+			},
+			FrameType::System => Message::System {text: frame.text()},
+			FrameType::Error => return Err(io::Error::new(ErrorKind::ConnectionAborted,format!("Peer closed the connection: {}",frame.text()))),
+			FrameType::Unknown(_) => Message::Unknown,
+			FrameType::Hello | FrameType::Handshake | FrameType::Sealed => return Err(unexpected_frame(frame,"a message")),
+		})
+	}
+}
+
+impl Receipt {
+	fn from_byte(byte: u8) -> Option<Receipt>{
+		match byte {
+			1 => Some(Receipt::Delivered),
+			2 => Some(Receipt::Read),
+			_ => None,
+		}
+	}
+	fn to_byte(self) -> u8{
+		match self {
+			Receipt::Delivered => 1,
+			Receipt::Read => 2,
+		}
+	}
+}
+
+/// Blocks until the next message arrives.
+pub fn read_message<T: Read>(stream: &mut T, max_payload: usize) -> io::Result<Message>{
+	Message::decode(&read_frame(stream,max_payload)?)
+}
+
+pub fn write_message<T: Write>(stream: &mut T, message: &Message) -> io::Result<()>{
+	let frame = message.encode();
+	write_frame(stream,frame.kind,&frame.payload)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::frame::DEFAULT_MAX_PAYLOAD;
+
+	fn round_trip(message: Message){
+		let mut bytes = vec![];
+		write_message(&mut bytes,&message).unwrap();
+		assert_eq!(read_message(&mut &bytes[..],DEFAULT_MAX_PAYLOAD).unwrap(),message);
+	}
+
+	#[test]
+	fn every_message(){
+		round_trip(Message::Text {id: 1, body: "hello ✓".into(), reply_to: None});
+		round_trip(Message::Text {id: 2, body: "me too".into(), reply_to: Some(Reply {own: false, id: 7})});
+		round_trip(Message::Text {id: 3, body: "".into(), reply_to: Some(Reply {own: true, id: u64::MAX})});
+		round_trip(Message::Nick {name: "alice".into()});
+		round_trip(Message::Ack {id: 4, receipt: Receipt::Delivered});
+		round_trip(Message::Ack {id: 4, receipt: Receipt::Read});
+		round_trip(Message::Ping);
+		round_trip(Message::Pong);
+		round_trip(Message::Goodbye {reason: "user left".into()});
+		round_trip(Message::Edit {id: 5, body: "fixed".into()});
+		round_trip(Message::Delete {id: 6});
+		round_trip(Message::Verify {confirmed: false});
+		round_trip(Message::Verify {confirmed: true});
+		round_trip(Message::Typing {typing: true});
+		round_trip(Message::Typing {typing: false});
+		round_trip(Message::System {text: "daemon restarted".into()});
+		round_trip(Message::Unknown);
+	}
+
+	#[test]
+	fn newer_peers(){
+		//kinds and extensions we dont know yet
+		assert_eq!(Message::decode(&Frame::new(FrameType::Control,vec![99])).unwrap(),Message::Unknown);
+		assert_eq!(Message::decode(&Frame::new(FrameType::Presence,vec![99,1])).unwrap(),Message::Unknown);
+		assert_eq!(Message::decode(&Frame::new(FrameType::Unknown(42),vec![1,2,3])).unwrap(),Message::Unknown);
+		assert_eq!(Message::decode(&Frame::new(FrameType::Control,Encoder::new().u8(ACK).u64(1).u8(9).finish())).unwrap(),Message::Unknown);
+		let text = Encoder::new().u64(1).string("hi").u8(99).finish();
+		assert_eq!(Message::decode(&Frame::new(FrameType::Text,text)).unwrap(),Message::Text {id: 1, body: "hi".into(), reply_to: None});
+	}
+
+	#[test]
+	fn errors_and_frames_that_arent_messages(){
+		let error = Message::decode(&Frame::new(FrameType::Error,b"too long".to_vec())).unwrap_err();
+		assert_eq!((error.kind(),error.to_string()),(ErrorKind::ConnectionAborted,"Peer closed the connection: too long".to_string()));
+		for kind in [FrameType::Hello,FrameType::Handshake,FrameType::Sealed]{
+			assert_eq!(Message::decode(&Frame::new(kind,vec![])).unwrap_err().kind(),ErrorKind::InvalidData);
+		}
+		//cut short
+		assert!(Message::decode(&Frame::new(FrameType::Text,vec![0,0,0])).is_err());
+		assert!(Message::decode(&Frame::new(FrameType::Control,vec![])).is_err());
+	}
+}
@@ -0,0 +1,362 @@
+//! Encryption for everything sent after the hello exchange.
+//!
+//! Straight after the hellos both sides run a `Noise_XX_25519_ChaChaPoly_BLAKE2s`
+//! handshake, carried in `Handshake` frames, with both hellos mixed into the
+//! prologue so neither can be tampered with. The connecting side is the
+//! initiator. After that every frame is sent inside a `Sealed` frame.
+//!
+//! If both sides were started with a passphrase, a SPAKE2 exchange runs first
+//! and its key is mixed into the handshake as a psk (`Noise_XXpsk2_...`), so
+//! only someone who knows the passphrase can finish it, and nobody can guess
+//! the passphrase offline from watching or even taking part in the exchange.
+//! The psk goes in at the end of the responder's message, so the connecting
+//! side knows the host has the passphrase before it trusts its key.
+//!
+//! The transport keys are taken out of the handshake rather than left inside
+//! snow, so a [`Session`] can be written out and handed to another process
+//! along with the socket, which is how vchatd passes a caller on to vchat.
+use std::io;
+use std::io::{Read,Write,ErrorKind};
+use chacha20poly1305::{ChaCha20Poly1305,KeyInit,Nonce};
+use chacha20poly1305::aead::Aead;
+use spake2::{Spake2,Ed25519Group,Password,Identity};
+use crate::frame::{Frame,FrameType,read_frame,read_frame_or_refuse,write_frame};
+use crate::hello::{Hello,unexpected_frame};
+use crate::message::Message;
+use crate::wire::{Encoder,Decoder};
+
+pub use snow::Keypair;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PSK_PARAMS: &str = "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s";
+const PAKE_IDENTITY: &[u8] = b"vanillachat passphrase";
+//largest handshake message snow will produce
+const HANDSHAKE_MESSAGE_LENGTH: usize = 65535;
+const KEY_LENGTH: usize = 32;
+
+/// One direction of an encrypted connection.
+#[derive(Clone)]
+pub struct CipherState {
+	key: [u8; KEY_LENGTH],
+	nonce: u64,
+}
+
+/// Everything needed to carry on an encrypted connection.
+#[derive(Clone)]
+pub struct Session {
+	pub sending: CipherState,
+	pub receiving: CipherState,
+	/// The peer's static public key.
+	pub remote_static: Vec<u8>,
+	/// Unique to this connection, and the same on both ends.
+	pub handshake_hash: Vec<u8>,
+}
+
+/// A handshake in progress, fed frames by whoever owns the socket.
+pub struct Handshake {
+	state: snow::HandshakeState,
+	//a failure is most likely a wrong passphrase
+	passphrase: bool,
+}
+
+/// The sending half of an encrypted connection.
+pub struct SealedWriter<T: Write> {
+	stream: T,
+	cipher: CipherState,
+}
+
+impl CipherState {
+	fn new(key: [u8; KEY_LENGTH]) -> CipherState{
+		CipherState {key, nonce: 0}
+	}
+	//the noise nonce format, 4 zero bytes then the counter little endian
+	fn next_nonce(&mut self) -> io::Result<Nonce>{
+		if self.nonce == u64::MAX {
+			return Err(io::Error::other("Ran out of nonces, reconnect to carry on."));
+		}
+		let mut nonce = [0; 12];
+		nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+		self.nonce += 1;
+		Ok(nonce.into())
+	}
+	/// Encrypts a frame into a `Sealed` frame.
+	pub fn seal(&mut self, frame: &Frame) -> io::Result<Frame>{
+		let mut plaintext = Vec::with_capacity(frame.payload.len() + 1);
+		plaintext.push(frame.kind.to_byte());
+		plaintext.extend_from_slice(&frame.payload);
+		let nonce = self.next_nonce()?;
+		let ciphertext = ChaCha20Poly1305::new(&self.key.into()).encrypt(&nonce,plaintext.as_slice())
+			.map_err(|_| io::Error::other("Could not encrypt frame."))?;
+		Ok(Frame::new(FrameType::Sealed,ciphertext))
+	}
+	/// Decrypts a `Sealed` frame, anything sent in the clear is refused.
+	pub fn open(&mut self, frame: &Frame) -> io::Result<Frame>{
+		if frame.kind != FrameType::Sealed {
+			return Err(unexpected_frame(frame,"an encrypted frame"));
+		}
+		let nonce = self.next_nonce()?;
+		let plaintext = ChaCha20Poly1305::new(&self.key.into()).decrypt(&nonce,frame.payload.as_slice())
+			.map_err(|_| io::Error::new(ErrorKind::InvalidData,"Frame failed to decrypt, the connection has been tampered with."))?;
+		match plaintext.split_first() {
+			Some((kind,payload)) => Ok(Frame::new(FrameType::from_byte(*kind),payload.to_vec())),
+			None => Err(io::Error::new(ErrorKind::InvalidData,"Sealed frame was empty.")),
+		}
+	}
+}
+
+impl Session {
+	/// Packs the session up to be handed to another process.
+	pub fn encode(&self) -> Vec<u8>{
+		Encoder::new()
+			.bytes(&self.sending.key)
+			.u64(self.sending.nonce)
+			.bytes(&self.receiving.key)
+			.u64(self.receiving.nonce)
+			.bytes(&self.remote_static)
+			.bytes(&self.handshake_hash)
+			.finish()
+	}
+	pub fn decode(payload: &[u8]) -> io::Result<Session>{
+		let mut decoder = Decoder::new(payload);
+		let mut cipher = || -> io::Result<CipherState>{
+			let key = decoder.bytes()?.try_into()
+				.map_err(|_| io::Error::new(ErrorKind::InvalidData,"Session key is the wrong length."))?;
+			Ok(CipherState {key, nonce: decoder.u64()?})
+		};
+		let sending = cipher()?;
+		let receiving = cipher()?;
+		Ok(Session {
+			sending,
+			receiving,
+			remote_static: decoder.bytes()?,
+			handshake_hash: decoder.bytes()?,
+		})
+	}
+}
+
+impl Handshake {
+	/// `initiator` is whoever opened the TCP connection, `psk` comes from [`passphrase_key`].
+	pub fn new(initiator: bool, keypair: &Keypair, initiator_hello: &Hello, responder_hello: &Hello, psk: Option<&[u8; 32]>) -> io::Result<Handshake>{
+		let params = match psk {
+			Some(_) => NOISE_PSK_PARAMS,
+			None => NOISE_PARAMS,
+		};
+		let prologue = Encoder::new()
+			.string(params)
+			.bytes(&initiator_hello.encode())
+			.bytes(&responder_hello.encode())
+			.finish();
+		let mut builder = snow::Builder::new(params.parse().map_err(noise_error)?)
+			.local_private_key(&keypair.private).map_err(noise_error)?
+			.prologue(&prologue).map_err(noise_error)?;
+		if let Some(psk) = psk {
+			builder = builder.psk(2,psk).map_err(noise_error)?;
+		}
+		let state = match initiator {
+			true => builder.build_initiator(),
+			false => builder.build_responder(),
+		}.map_err(noise_error)?;
+		Ok(Handshake {state, passphrase: psk.is_some()})
+	}
+	/// The next frame to send, or `None` if we are waiting on the peer.
+	pub fn next_frame(&mut self) -> io::Result<Option<Frame>>{
+		if self.is_finished() || !self.state.is_my_turn() {
+			return Ok(None);
+		}
+		let mut message = vec![0; HANDSHAKE_MESSAGE_LENGTH];
+		let length = self.state.write_message(&[],&mut message).map_err(noise_error)?;
+		message.truncate(length);
+		Ok(Some(Frame::new(FrameType::Handshake,message)))
+	}
+	/// Takes in a frame from the peer.
+	pub fn receive_frame(&mut self, frame: &Frame) -> io::Result<()>{
+		if frame.kind != FrameType::Handshake {
+			return Err(unexpected_frame(frame,"a handshake"));
+		}
+		let mut payload = vec![0; HANDSHAKE_MESSAGE_LENGTH];
+		match self.state.read_message(&frame.payload,&mut payload) {
+			Ok(_) => Ok(()),
+			Err(_) if self.passphrase => Err(io::Error::new(ErrorKind::PermissionDenied,"Passphrase did not match, or someone is interfering with the connection.")),
+			Err(e) => Err(noise_error(e)),
+		}
+	}
+	pub fn is_finished(&self) -> bool{
+		self.state.is_handshake_finished()
+	}
+	pub fn finish(mut self) -> io::Result<Session>{
+		if !self.is_finished() {
+			return Err(io::Error::other("Handshake is not finished."));
+		}
+		let remote_static = self.state.get_remote_static()
+			.ok_or_else(|| io::Error::new(ErrorKind::InvalidData,"Peer did not send a static key."))?
+			.to_vec();
+		let handshake_hash = self.state.get_handshake_hash().to_vec();
+		//first key is initiator to responder
+		let (initiator_key,responder_key) = self.state.dangerously_get_raw_split();
+		let (sending,receiving) = match self.state.is_initiator() {
+			true => (initiator_key,responder_key),
+			false => (responder_key,initiator_key),
+		};
+		Ok(Session {
+			sending: CipherState::new(sending),
+			receiving: CipherState::new(receiving),
+			remote_static,
+			handshake_hash,
+		})
+	}
+}
+
+impl<T: Write> SealedWriter<T> {
+	pub fn new(stream: T, cipher: CipherState) -> SealedWriter<T>{
+		SealedWriter {stream, cipher}
+	}
+	pub fn write_frame(&mut self, kind: FrameType, payload: &[u8]) -> io::Result<()>{
+		let sealed = self.cipher.seal(&Frame::new(kind,payload.to_vec()))?;
+		write_frame(&mut self.stream,sealed.kind,&sealed.payload)
+	}
+	pub fn write_message(&mut self, message: &Message) -> io::Result<()>{
+		let frame = message.encode();
+		self.write_frame(frame.kind,&frame.payload)
+	}
+	pub fn get_ref(&self) -> &T{
+		&self.stream
+	}
+}
+
+/// A fresh static key pair.
+pub fn generate_keypair() -> io::Result<Keypair>{
+	snow::Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?)
+		.generate_keypair()
+		.map_err(noise_error)
+}
+
+/// Runs a whole handshake on a blocking stream.
+pub fn handshake<T: Read + Write>(stream: &mut T, mut handshake: Handshake, max_payload: usize) -> io::Result<Session>{
+	while !handshake.is_finished() {
+		match handshake.next_frame()? {
+			Some(frame) => write_frame(stream,frame.kind,&frame.payload)?,
+			None => {
+				if let Err(e) = read_frame(stream,max_payload).and_then(|frame| handshake.receive_frame(&frame)) {
+					//they may think they are done already, so tell them why
+					let _ = write_frame(stream,FrameType::Error,e.to_string().as_bytes());
+					return Err(e);
+				}
+			},
+		}
+	}
+	handshake.finish()
+}
+
+/// Swaps SPAKE2 messages on a blocking stream, giving a key for the handshake
+/// that only matches the peer's if they used the same passphrase.
+pub fn passphrase_key<T: Read + Write>(stream: &mut T, passphrase: &str, max_payload: usize) -> io::Result<[u8; 32]>{
+	let (state,message) = Spake2::<Ed25519Group>::start_symmetric(&Password::new(passphrase),&Identity::new(PAKE_IDENTITY));
+	write_frame(stream,FrameType::Handshake,&message)?;
+	let frame = read_frame_or_refuse(stream,max_payload)?;
+	if frame.kind != FrameType::Handshake {
+		return Err(unexpected_frame(&frame,"a passphrase exchange"));
+	}
+	let key = state.finish(&frame.payload)
+		.map_err(|e| io::Error::new(ErrorKind::InvalidData,format!("Passphrase exchange failed: {e:?}")))?;
+	key.try_into().map_err(|_| io::Error::other("Passphrase exchange gave a key of the wrong length."))
+}
+
+fn noise_error(e: snow::Error) -> io::Error{
+	io::Error::new(ErrorKind::InvalidData,format!("Encrypted handshake failed: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::net::UnixStream;
+	use std::thread;
+	use crate::frame::DEFAULT_MAX_PAYLOAD;
+
+	//one end of a connection, with the passphrase it was started with
+	fn side(mut stream: UnixStream, initiator: bool, keypair: Keypair, passphrase: Option<&str>) -> io::Result<Session>{
+		let (alice,bob) = (Hello::new("alice","0.1.0"),Hello::new("bob","0.1.0"));
+		let psk = passphrase.map(|passphrase| passphrase_key(&mut stream,passphrase,DEFAULT_MAX_PAYLOAD)).transpose()?;
+		handshake(&mut stream,Handshake::new(initiator,&keypair,&alice,&bob,psk.as_ref())?,DEFAULT_MAX_PAYLOAD)
+	}
+
+	//how one end's handshake went, and its public key
+	type Side = (io::Result<Session>,Vec<u8>);
+
+	//runs both ends over a socket pair
+	fn connect(initiator: Option<&str>, responder: Option<&'static str>) -> (Side,Side){
+		let (initiator_stream,responder_stream) = UnixStream::pair().unwrap();
+		let (initiator_keypair,responder_keypair) = (generate_keypair().unwrap(),generate_keypair().unwrap());
+		let (initiator_public,responder_public) = (initiator_keypair.public.clone(),responder_keypair.public.clone());
+		let responder_thread = thread::spawn(move || side(responder_stream,false,responder_keypair,responder));
+		let initiator_session = side(initiator_stream,true,initiator_keypair,initiator);
+		((initiator_session,initiator_public),(responder_thread.join().unwrap(),responder_public))
+	}
+
+	fn text(body: &[u8]) -> Frame{
+		Frame::new(FrameType::Text,body.to_vec())
+	}
+
+	#[test]
+	fn loopback_handshake(){
+		let ((initiator,initiator_public),(responder,responder_public)) = connect(None,None);
+		let (mut initiator,mut responder) = (initiator.unwrap(),responder.unwrap());
+		assert_eq!(initiator.remote_static,responder_public);
+		assert_eq!(responder.remote_static,initiator_public);
+		assert_eq!(initiator.handshake_hash,responder.handshake_hash);
+		let sealed = initiator.sending.seal(&text(b"hello")).unwrap();
+		assert_eq!(sealed.kind,FrameType::Sealed);
+		assert_ne!(sealed.payload[1..],b"hello"[..]);
+		let opened = responder.receiving.open(&sealed).unwrap();
+		assert_eq!((opened.kind,opened.payload.as_slice()),(FrameType::Text,&b"hello"[..]));
+		let sealed = responder.sending.seal(&text(b"hi")).unwrap();
+		assert_eq!(initiator.receiving.open(&sealed).unwrap().payload,b"hi");
+	}
+
+	#[test]
+	fn sessions_carry_on_after_a_handover(){
+		let ((initiator,_),(responder,_)) = connect(None,None);
+		let (mut initiator,mut daemon) = (initiator.unwrap(),responder.unwrap());
+		//a frame each way while they are on hold
+		let sealed = initiator.sending.seal(&text(b"anyone there")).unwrap();
+		daemon.receiving.open(&sealed).unwrap();
+		let sealed = daemon.sending.seal(&text(b"on hold")).unwrap();
+		initiator.receiving.open(&sealed).unwrap();
+		//then vchat picks up where the daemon left off
+		let mut vchat = Session::decode(&daemon.encode()).unwrap();
+		assert_eq!((vchat.remote_static.clone(),vchat.handshake_hash.clone()),(daemon.remote_static,daemon.handshake_hash));
+		let sealed = initiator.sending.seal(&text(b"hello")).unwrap();
+		assert_eq!(vchat.receiving.open(&sealed).unwrap().payload,b"hello");
+		let sealed = vchat.sending.seal(&text(b"hi")).unwrap();
+		assert_eq!(initiator.receiving.open(&sealed).unwrap().payload,b"hi");
+	}
+
+	#[test]
+	fn tampered_and_replayed_frames_are_refused(){
+		let ((initiator,_),(responder,_)) = connect(None,None);
+		let (mut initiator,responder) = (initiator.unwrap(),responder.unwrap());
+		let first = initiator.sending.seal(&text(b"one")).unwrap();
+		let second = initiator.sending.seal(&text(b"pay alice")).unwrap();
+		let mut tampered = second.clone();
+		tampered.payload[3] ^= 1;
+		let mut receiving = responder.receiving.clone();
+		receiving.open(&first).unwrap();
+		assert_eq!(receiving.clone().open(&tampered).unwrap_err().kind(),ErrorKind::InvalidData);
+		//sent again
+		assert!(receiving.clone().open(&first).is_err());
+		assert_eq!(receiving.open(&second).unwrap().payload,b"pay alice");
+		//out of order
+		assert!(responder.receiving.clone().open(&second).is_err());
+		//and anything sent in the clear
+		assert!(responder.receiving.clone().open(&text(b"hello")).is_err());
+	}
+
+	#[test]
+	fn passphrases_must_match(){
+		let ((initiator,_),(responder,_)) = connect(Some("apple"),Some("apple"));
+		assert_eq!(initiator.unwrap().handshake_hash,responder.unwrap().handshake_hash);
+		let ((initiator,_),(responder,_)) = connect(Some("apple"),Some("pear"));
+		//the caller finds out from the host's half of the handshake, before trusting its key
+		assert_eq!(initiator.err().unwrap().kind(),ErrorKind::PermissionDenied);
+		assert!(responder.is_err());
+	}
+}
@@ -0,0 +1,83 @@
+//! Helpers for packing fields into frame payloads.
+//!
+//! Integers are big endian, strings and byte strings are prefixed with a u32 length.
+use std::io;
+use std::io::ErrorKind;
+
+#[derive(Debug,Default)]
+pub struct Encoder {
+	bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Decoder<'a> {
+	bytes: &'a [u8],
+}
+
+impl Encoder {
+	pub fn new() -> Encoder{
+		Encoder {bytes: vec![]}
+	}
+	pub fn u8(mut self, value: u8) -> Encoder{
+		self.bytes.push(value);
+		self
+	}
+	pub fn u32(mut self, value: u32) -> Encoder{
+		self.bytes.extend_from_slice(&value.to_be_bytes());
+		self
+	}
+	pub fn u64(mut self, value: u64) -> Encoder{
+		self.bytes.extend_from_slice(&value.to_be_bytes());
+		self
+	}
+	pub fn bytes(mut self, value: &[u8]) -> Encoder{
+		self = self.u32(value.len().try_into().unwrap_or(u32::MAX));
+		self.bytes.extend_from_slice(value);
+		self
+	}
+	pub fn string(self, value: &str) -> Encoder{
+		self.bytes(value.as_bytes())
+	}
+	pub fn finish(self) -> Vec<u8>{
+		self.bytes
+	}
+}
+
+impl<'a> Decoder<'a> {
+	pub fn new(bytes: &'a [u8]) -> Decoder<'a>{
+		Decoder {bytes}
+	}
+	fn take(&mut self, count: usize) -> io::Result<&'a [u8]>{
+		if self.bytes.len() < count {
+			return Err(io::Error::new(ErrorKind::InvalidData,"Payload ended early."));
+		}
+		let (taken,rest) = self.bytes.split_at(count);
+		self.bytes = rest;
+		Ok(taken)
+	}
+	pub fn u8(&mut self) -> io::Result<u8>{
+		Ok(self.take(1)?[0])
+	}
+	pub fn u32(&mut self) -> io::Result<u32>{
+		Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+	}
+	pub fn u64(&mut self) -> io::Result<u64>{
+		Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+	}
+	pub fn bytes(&mut self) -> io::Result<Vec<u8>>{
+		let length = self.u32()? as usize;
+		Ok(self.take(length)?.to_vec())
+	}
+	/// Decodes with the same lossy UTF-8 policy as [`crate::Frame::text`].
+	pub fn string(&mut self) -> io::Result<String>{
+		let length = self.u32()? as usize;
+		Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+	}
+	pub fn is_empty(&self) -> bool{
+		self.bytes.is_empty()
+	}
+	/// Everything that has not been decoded yet.
+	pub fn rest(&mut self) -> &'a [u8]{
+		std::mem::take(&mut self.bytes)
+	}
+}